@@ -0,0 +1,118 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * Linked clones. The new config is the source config with its own vmname
+ * and every writable disk replaced by a qcow2 overlay whose base image is
+ * the source disk. Read only disks are shared as they are.
+ */
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use crate::qemuconfig::{self, Disk};
+use crate::runtime::RuntimeDir;
+use crate::error::{Error, ErrorKind};
+
+//keys the disks of the source config can be in, replaced by the overlays
+const DISK_KEYS: [&str; 5] = ["disk_img", "disk2_img", "disk", "disk2", "disks"];
+
+fn disk_entry(disk: &Disk) -> Result<toml::Value, Error> {
+    let mut value = toml::Value::try_from(disk).map_err(|e| Error::new(ErrorKind::Misc, format!("Could not write disk {}", disk.path)).with_source(e))?;
+    //leave out what would be the default anyway
+    if let Some(table) = value.as_table_mut() {
+        let defaults: Vec<String> = table.iter()
+            .filter(|(_, v)| v.as_str() == Some("") || v.as_bool() == Some(false) || v.as_integer() == Some(0))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in defaults.iter() {
+            table.remove(key);
+        }
+    }
+    Ok(value)
+}
+
+pub fn clone_vm(src: &str, dst: &str) -> Result<(), Error> {
+    let config = qemuconfig::setup_options(src)?;
+    if let Some(pid) = RuntimeDir::for_vm(&config.vmname)?.running_pid() {
+        return Err(Error::new(ErrorKind::AlreadyRunning, format!("{} is running with pid {}, stop it before cloning its disks", config.vmname, pid)));
+    }
+    let dst_path = Path::new(dst);
+    if dst_path.exists() {
+        return Err(Error::new(ErrorKind::OpenConfigFile, format!("{} already exists", dst)));
+    }
+    let vmname = dst_path.file_stem().and_then(OsStr::to_str).unwrap_or("clone").to_string();
+    //the overlays go next to the new config, with absolute paths so it can be run from anywhere
+    let dst_dir = match dst_path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let dst_dir = fs::canonicalize(dst_dir).map_err(|e| Error::new(ErrorKind::NoSuchFile, format!("Can't write a clone to {}", dst_dir.display())).with_source(e))?;
+
+    let text = fs::read_to_string(src).map_err(|e| Error::new(ErrorKind::ReadConfigFile, format!("Could not read {}", src)).with_source(e))?;
+    let mut value: toml::Value = toml::from_str(&text).map_err(|e| Error::new(ErrorKind::ReadConfigFile, format!("Only toml configs can be cloned, {}", src)).with_source(e))?;
+    let table = match value.as_table_mut() {
+        Some(t) => t,
+        None => {
+            return Err(Error::new(ErrorKind::ReadConfigFile, format!("{} is not a config file", src)));
+        }
+    };
+    for key in DISK_KEYS.iter() {
+        table.remove(*key);
+    }
+    table.insert(String::from("vmname"), toml::Value::String(vmname.clone()));
+
+    let mut disks = Vec::new();
+    for (i, disk) in config.disks.iter().enumerate() {
+        if disk.readonly {
+            disks.push(disk_entry(disk)?);
+            continue;
+        }
+        let name = if i == 0 { format!("{}.qcow2", vmname) } else { format!("{}-disk{}.qcow2", vmname, i) };
+        let mut overlay = disk.clone();
+        overlay.path = dst_dir.join(name).display().to_string();
+        overlay.format = String::new();
+        overlay.base_image = match fs::canonicalize(&disk.path) {
+            Ok(p) => p.display().to_string(),
+            Err(e) => {
+                return Err(Error::new(ErrorKind::NoSuchFile, format!("Can't clone {}", disk.path)).with_source(e));
+            }
+        };
+        if Path::new(&overlay.path).exists() {
+            return Err(Error::new(ErrorKind::NoSuchFile, format!("{} already exists", overlay.path)));
+        }
+        qemuconfig::handle_disk_image(&config.qemu_img_path, &overlay)?;
+        println!("{} is an overlay of {}", overlay.path, overlay.base_image);
+        disks.push(disk_entry(&overlay)?);
+    }
+    if !disks.is_empty() {
+        table.insert(String::from("disks"), toml::Value::Array(disks));
+    }
+
+    let out = toml::to_string(&value).map_err(|e| Error::new(ErrorKind::Misc, format!("Could not write {}", dst)).with_source(e))?;
+    fs::write(dst_path, out).map_err(|e| Error::new(ErrorKind::Misc, format!("Could not write {}", dst)).with_source(e))?;
+    println!("Cloned {} to {} as {}", config.vmname, dst, vmname);
+    Ok(())
+}
@@ -0,0 +1,230 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+
+/*
+ * Config options that take one of a fixed set of values. They are read as
+ * strings and matched case insensitively against each value's name and
+ * aliases, so an unknown value fails when the config is loaded, with the
+ * valid names in the error. They are written back out under their names.
+ */
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+macro_rules! config_enum {
+    ($name:ident, $key:literal,
+     { $($variant:ident => $value:literal $(| $alias:literal)*),+ $(,)? }) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(try_from = "String", into = "String")]
+        pub enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            pub const CHOICES: &'static [&'static str] = &[$($value),+];
+
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $($name::$variant => $value),+
+                }
+            }
+        }
+
+        impl std::convert::TryFrom<String> for $name {
+            type Error = String;
+
+            fn try_from(value: String) -> Result<$name, String> {
+                match value.to_lowercase().as_str() {
+                    $($value $(| $alias)* => Ok($name::$variant),)+
+                    _ => Err(format!("{} {:?} IS UNKNOWN, USE ONE OF {}", $key, value, $name::CHOICES.join(", "))),
+                }
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> String {
+                value.as_str().to_string()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+    };
+}
+
+//the emulated graphics card
+config_enum!(DisplayDevice, "display_device", {
+    Vga => "vga" | "std",
+    IsaVga => "isa-vga",
+    Cirrus => "cirrus" | "cirrus-vga",
+    IsaCirrus => "isa-cirrus" | "isa-cirrus-vga",
+    Bochs => "bochs" | "bochs-display",
+    Ati => "ati" | "ati-vga",
+    Vmware => "vmware" | "vmware-svga" | "vmware-vga",
+    Qxl => "qxl" | "qxl-vga",
+    Virtio => "virtio" | "virtio-vga",
+});
+
+impl DisplayDevice {
+    pub fn is_isa(self) -> bool {
+        matches!(self, DisplayDevice::IsaVga | DisplayDevice::IsaCirrus)
+    }
+}
+
+//the emulated sound card
+config_enum!(Audio, "audio", {
+    IntelHda => "intel-hda" | "hda",
+    Ich9Hda => "ich9-intel-hda" | "ich9-hda",
+    Ac97 => "ac97",
+    Sb16 => "sb16",
+    Es1370 => "es1370",
+    Gus => "gus",
+    Adlib => "adlib",
+    Cs4231a => "cs4231a",
+    None => "none",
+});
+
+impl Audio {
+    //qemu's device name, None has no device
+    pub fn device(self) -> Option<&'static str> {
+        match self {
+            Audio::Ac97 => Some("AC97"),
+            Audio::None => None,
+            a => Some(a.as_str()),
+        }
+    }
+
+    //hda controllers need a codec, the codec is what gets the audio backend
+    pub fn is_hda(self) -> bool {
+        matches!(self, Audio::IntelHda | Audio::Ich9Hda)
+    }
+}
+
+//where the guest's sound goes on the host, qemu's audiodev backends
+config_enum!(AudioOutput, "audio_output", {
+    Pa => "pa" | "pulseaudio" | "pulse",
+    Pipewire => "pipewire",
+    Alsa => "alsa",
+    Oss => "oss",
+    Jack => "jack",
+    Sdl => "sdl",
+    Coreaudio => "coreaudio",
+    Dsound => "dsound",
+    Spice => "spice",
+    Dbus => "dbus",
+    Wav => "wav",
+    None => "none",
+});
+
+//the bus a disk is attached to
+config_enum!(DiskInterface, "disk_interface", {
+    Virtio => "virtio" | "none" | "virtio-blk",
+    Ide => "ide",
+    //ide on the isapc machine
+    IsaIde => "isa-ide" | "isa",
+    Scsi => "scsi",
+    Nvme => "nvme",
+    Ahci => "ahci" | "sata",
+    VirtioScsi => "virtio-scsi",
+    UsbStorage => "usb-storage" | "usb",
+});
+
+impl DiskInterface {
+    pub fn is_pci(self) -> bool {
+        matches!(self, DiskInterface::Virtio | DiskInterface::Nvme | DiskInterface::Ahci | DiskInterface::VirtioScsi)
+    }
+
+    pub fn is_ide(self) -> bool {
+        matches!(self, DiskInterface::Ide | DiskInterface::IsaIde)
+    }
+}
+
+//how qemu shows the vm's screen, qemu's -display types
+config_enum!(Output, "output", {
+    Sdl => "sdl",
+    Gtk => "gtk",
+    SpiceApp => "spice-app",
+    Curses => "curses",
+    EglHeadless => "egl-headless",
+    Cocoa => "cocoa",
+    Dbus => "dbus",
+    None => "none",
+});
+
+//firmware, auto is legacy unless secure_boot is on
+config_enum!(Boot, "boot", {
+    Auto => "auto" | "",
+    Legacy => "legacy" | "bios",
+    Efi => "efi" | "uefi",
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[derive(Deserialize, Debug)]
+    struct Options {
+        audio_output: Option<AudioOutput>,
+        boot: Option<Boot>,
+    }
+
+    #[test]
+    fn aliases_parse_to_the_same_value() {
+        for alias in ["pa", "pulseaudio", "pulse"].iter() {
+            assert_eq!(AudioOutput::try_from(alias.to_string()), Ok(AudioOutput::Pa));
+        }
+        assert_eq!(DiskInterface::try_from(String::from("sata")), Ok(DiskInterface::Ahci));
+        assert_eq!(DiskInterface::try_from(String::from("isa")), Ok(DiskInterface::IsaIde));
+        assert_eq!(Boot::try_from(String::from("")), Ok(Boot::Auto));
+    }
+
+    #[test]
+    fn values_are_case_insensitive() {
+        assert_eq!(Boot::try_from(String::from("UEFI")), Ok(Boot::Efi));
+        assert_eq!(DisplayDevice::try_from(String::from("ISA-Cirrus-VGA")), Ok(DisplayDevice::IsaCirrus));
+    }
+
+    #[test]
+    fn unknown_value_lists_the_choices() {
+        let e = Audio::try_from(String::from("sb17")).unwrap_err();
+        assert_eq!(e, format!("audio \"sb17\" IS UNKNOWN, USE ONE OF {}", Audio::CHOICES.join(", ")));
+        assert!(e.contains("intel-hda, ich9-intel-hda, ac97, sb16"));
+    }
+
+    #[test]
+    fn read_and_written_by_name() {
+        let options: Options = toml::from_str("audio_output = \"pulse\"\nboot = \"bios\"").unwrap();
+        assert_eq!((options.audio_output, options.boot), (Some(AudioOutput::Pa), Some(Boot::Legacy)));
+        assert_eq!(String::from(AudioOutput::Pa), "pa");
+        assert_eq!(Boot::Legacy.to_string(), "legacy");
+        let e = toml::from_str::<Options>("boot = \"coreboot\"").unwrap_err();
+        assert!(e.to_string().contains("boot \"coreboot\" IS UNKNOWN, USE ONE OF auto, legacy, efi"));
+    }
+}
@@ -0,0 +1,185 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+
+/*
+ * Keys that none of the config structs know. serde skips them without a
+ * word, so a typo like cpu_core = 4 would just be ignored. Each file is
+ * read on its own to say where the key is, and the nearest known key by
+ * edit distance is offered in its place.
+ */
+
+use serde_json::Value;
+use std::fmt;
+use crate::qemuconfig::known_keys;
+
+#[derive(Debug, Clone)]
+pub struct UnknownKey {
+    pub file: String,
+    pub section: String, //empty for the top of a vm file
+    pub key: String,
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if !self.section.is_empty() {
+            write!(f, " {}", self.section)?;
+        }
+        write!(f, ": unknown key {}", self.key)?;
+        match &self.suggestion {
+            Some(s) => write!(f, ", did you mean {}?", s),
+            None => Ok(()),
+        }
+    }
+}
+
+//levenshtein distance, counted in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+//the closest known key, if it is close enough to be a typo of it
+fn suggest(key: &str, known: &[String]) -> Option<String> {
+    let key = key.to_lowercase();
+    let limit = std::cmp::max(2, key.chars().count() / 3);
+    known.iter()
+        .map(|k| (edit_distance(&key, k), k))
+        .filter(|(d, _)| *d <= limit)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| k.clone())
+}
+
+/*
+ * Checks the keys of one vm config table, along with the tables in its
+ * [[disks]], [[serial]], [[shares]] and [[port_forwards]] lists. section
+ * is the tweak file section the table came from, empty for a vm file, and
+ * is put in front of the list names.
+ */
+pub fn find_unknown_keys(file: &str, section: &str, table: &Value) -> Vec<UnknownKey> {
+    let mut found = Vec::new();
+    let table = match table.as_object() {
+        Some(t) => t,
+        None => return found,
+    };
+    let top = known_keys("");
+    let label = if section.is_empty() { String::new() } else { format!("[{}]", section) };
+    for (key, value) in table.iter() {
+        if !top.contains(key) {
+            found.push(UnknownKey {
+                file: file.to_string(),
+                section: label.clone(),
+                key: key.clone(),
+                suggestion: suggest(key, &top),
+            });
+            continue;
+        }
+        let list = match value.as_array() {
+            Some(l) => l,
+            None => continue,
+        };
+        let fields = known_keys(key);
+        if fields.is_empty() {
+            continue;
+        }
+        let name = if section.is_empty() { key.clone() } else { format!("{}.{}", section, key) };
+        for (n, entry) in list.iter().enumerate() {
+            for field in entry.as_object().map(|e| e.keys()).into_iter().flatten() {
+                if !fields.contains(field) {
+                    found.push(UnknownKey {
+                        file: file.to_string(),
+                        section: format!("[[{}]] {}", name, n),
+                        key: field.clone(),
+                        suggestion: suggest(field, &fields),
+                    });
+                }
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("ram", ""), 3);
+        assert_eq!(edit_distance("", "ram"), 3);
+        assert_eq!(edit_distance("cpu_core", "cpu_cores"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("tmp", "tpm"), 2);
+        assert_eq!(edit_distance("usb", "usb"), 0);
+    }
+
+    #[test]
+    fn typo_gets_a_suggestion() {
+        let top = known_keys("");
+        assert_eq!(suggest("cpu_core", &top).as_deref(), Some("cpu_cores"));
+        assert_eq!(suggest("CPU_CORES", &top).as_deref(), Some("cpu_cores"));
+        assert_eq!(suggest("secureboot", &top).as_deref(), Some("secure_boot"));
+    }
+
+    #[test]
+    fn far_off_key_gets_none() {
+        let top = known_keys("");
+        assert_eq!(suggest("frobnicate_everything", &top), None);
+        assert_eq!(suggest("my_custom_setting", &top), None);
+    }
+
+    #[test]
+    fn keys_in_lists_and_sections() {
+        let table = json!({
+            "cpu_core": 4,
+            "ram": "4G",
+            "disks": [ { "path": "a.qcow2", "sise": "10G" } ],
+        });
+        let found = find_unknown_keys("vm.toml", "linux", &table);
+        assert_eq!(found.len(), 2);
+        let names: Vec<String> = found.iter().map(|k| k.to_string()).collect();
+        assert!(names.contains(&String::from("vm.toml [linux]: unknown key cpu_core, did you mean cpu_cores?")));
+        assert!(names.contains(&String::from("vm.toml [[linux.disks]] 0: unknown key sise, did you mean size?")));
+        assert!(find_unknown_keys("vm.toml", "", &json!({ "ram": "4G" })).is_empty());
+    }
+}
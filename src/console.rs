@@ -0,0 +1,169 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * Attach the terminal to a running vm's first serial port.
+ * The terminal is put in raw mode so keys like ctrl-c reach the guest,
+ * ctrl-] detaches.
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::thread;
+use crate::runtime::RuntimeDir;
+use crate::error::{Error, ErrorKind};
+
+const DETACH_KEY: u8 = 0x1d; //ctrl-]
+
+enum SerialConnection {
+    Pty(File),
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl SerialConnection {
+    fn split(&self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        Ok(match self {
+            SerialConnection::Pty(f) => (Box::new(f.try_clone()?), Box::new(f.try_clone()?)),
+            SerialConnection::Unix(s) => (Box::new(s.try_clone()?), Box::new(s.try_clone()?)),
+            SerialConnection::Tcp(s) => (Box::new(s.try_clone()?), Box::new(s.try_clone()?)),
+        })
+    }
+}
+
+//raw mode on a terminal, handing back the old settings to restore
+fn make_raw(fd: i32) -> Option<libc::termios> {
+    unsafe {
+        if libc::isatty(fd) != 1 {
+            return None;
+        }
+        let mut old: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut old) != 0 {
+            return None;
+        }
+        let mut raw = old;
+        libc::cfmakeraw(&mut raw);
+        libc::tcsetattr(fd, libc::TCSANOW, &raw);
+        Some(old)
+    }
+}
+
+fn restore(fd: i32, saved: &Option<libc::termios>) {
+    if let Some(t) = saved {
+        unsafe {
+            libc::tcsetattr(fd, libc::TCSANOW, t);
+        }
+    }
+}
+
+//chardev filenames look like "pty:/dev/pts/3", "unix:/path,server=on" or "tcp:127.0.0.1:4555,server=on"
+fn open_serial(filename: &str) -> Result<SerialConnection, Error> {
+    //socket chardevs nobody is connected to yet get a prefix
+    let filename = filename.trim_start_matches("disconnected:");
+    let (kind, rest) = match filename.find(':') {
+        Some(pos) => (&filename[..pos], &filename[pos + 1..]),
+        None => (filename, ""),
+    };
+    let target = rest.split(',').next().unwrap_or("");
+    let r = match kind {
+        "pty" => OpenOptions::new().read(true).write(true).open(target).map(|f| {
+            //the pty side we open has its own line discipline, it must not echo or cook
+            make_raw(f.as_raw_fd());
+            SerialConnection::Pty(f)
+        }),
+        "unix" => UnixStream::connect(target).map(SerialConnection::Unix),
+        "tcp" | "telnet" => TcpStream::connect(target).map(SerialConnection::Tcp),
+        _ => {
+            return Err(Error::new(ErrorKind::UnknownSerialBackend, format!("Can't attach to a serial port on {}", filename)));
+        }
+    };
+    r.map_err(|e| Error::new(ErrorKind::NoSuchFile, format!("Could not open serial port {}", filename)).with_source(e))
+}
+
+pub fn attach_console(rt: &RuntimeDir) -> Result<(), Error> {
+    if rt.running_pid().is_none() {
+        return Err(Error::new(ErrorKind::Misc, format!("{} is not running", rt.vmname())));
+    }
+    let serial = rt.read_serial();
+    let filename = match serial.get("serial0") {
+        Some(f) => f.clone(),
+        None => {
+            return Err(Error::new(ErrorKind::UnknownSerialBackend, format!("{} has no serial port recorded", rt.vmname())));
+        }
+    };
+    let conn = open_serial(&filename)?;
+    let (mut from_guest, mut to_guest) = conn.split().map_err(|e| Error::new(ErrorKind::Misc, format!("Could not open serial port {} twice", filename)).with_source(e))?;
+
+    eprintln!("Connected to {} serial0 ({}), ctrl-] to detach\r", rt.vmname(), filename);
+    let stdin_fd = io::stdin().as_raw_fd();
+    let saved = make_raw(stdin_fd);
+
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        let mut stdout = io::stdout();
+        loop {
+            match from_guest.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if stdout.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                    let _ = stdout.flush();
+                }
+            }
+        }
+        //the guest side went away, nothing left to do
+        restore(stdin_fd, &saved);
+        eprintln!("\r\nSerial port closed");
+        process::exit(0);
+    });
+
+    let mut buf = [0u8; 1024];
+    let mut stdin = io::stdin();
+    loop {
+        let n = match stdin.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        match buf[..n].iter().position(|b| *b == DETACH_KEY) {
+            Some(pos) => {
+                let _ = to_guest.write_all(&buf[..pos]);
+                break;
+            },
+            None => {
+                if to_guest.write_all(&buf[..n]).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    restore(stdin_fd, &saved);
+    eprintln!("\r\nDetached from {}", rt.vmname());
+    Ok(())
+}
@@ -0,0 +1,198 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * Disk maintenance through qemu-img. Everything but info writes to the
+ * image, so it is refused while the vm runs, going by its pidfile, or
+ * while anything else holds qemu's lock on the image.
+ */
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use crate::diskformat;
+use crate::qemuconfig::{Disk, QuickEmuConfig};
+use crate::qmp::ImageInfo;
+use crate::runtime::RuntimeDir;
+use crate::snapshot::format_size;
+use crate::utils::run_qemu_img;
+use crate::error::{Error, ErrorKind};
+
+pub enum DiskAction {
+    Info,
+    Resize(String),
+    Convert(String, Option<String>), //format, output path
+    Compact(bool, bool), //compress, drop the image's snapshots
+}
+
+fn image_info(config: &QuickEmuConfig, path: &str) -> Result<ImageInfo, Error> {
+    let out = run_qemu_img(&config.qemu_img_path, &["info", "--output=json", "-U", path])?;
+    serde_json::from_str(&out).map_err(|e| Error::new(ErrorKind::QemuImg, format!("Could not read qemu-img info for {}", path)).with_source(e))
+}
+
+//qemu takes a write lock on its images, qemu-img info without -U fails on a locked one
+fn image_locked(config: &QuickEmuConfig, path: &str) -> bool {
+    match Command::new(&config.qemu_img_path).args(["info", path]).output() {
+        Ok(r) => !r.status.success() && String::from_utf8_lossy(&r.stderr).contains("lock"),
+        Err(_) => false,
+    }
+}
+
+fn check_not_in_use(config: &QuickEmuConfig, rt: &RuntimeDir, disk: &Disk) -> Result<(), Error> {
+    if let Some(pid) = rt.running_pid() {
+        return Err(Error::new(ErrorKind::AlreadyRunning, format!("{} is running with pid {}, stop it before changing {}", config.vmname, pid, disk.path)));
+    }
+    if image_locked(config, &disk.path) {
+        return Err(Error::new(ErrorKind::AlreadyRunning, format!("{} is locked, another qemu is using it", disk.path)));
+    }
+    Ok(())
+}
+
+fn print_info(config: &QuickEmuConfig, index: usize, disk: &Disk) -> Result<(), Error> {
+    let info = image_info(config, &disk.path)?;
+    println!("disk {}: {}", index, disk.path);
+    println!("  format: {}", info.format);
+    println!("  virtual size: {}", format_size(info.virtual_size));
+    println!("  on disk: {}", format_size(info.actual_size));
+    if let Some(backing) = &info.backing_file {
+        println!("  base image: {} ({})", backing, info.backing_format.as_deref().unwrap_or("?"));
+    }
+    if !info.snapshots.is_empty() {
+        println!("  snapshots: {}", info.snapshots.len());
+    }
+    Ok(())
+}
+
+//only growing, shrinking a disk cuts off whatever the guest keeps at its end
+fn resize(config: &QuickEmuConfig, disk: &Disk, size: &str) -> Result<(), Error> {
+    if size.starts_with('-') {
+        return Err(Error::new(ErrorKind::QemuImg, "Disks can only be grown, shrink the guest's partitions and convert instead"));
+    }
+    let format = image_info(config, &disk.path)?.format;
+    run_qemu_img(&config.qemu_img_path, &["resize", "-f", &format, &disk.path, size])?;
+    let info = image_info(config, &disk.path)?;
+    println!("{} is now {}, grow the partitions in the guest to use it", disk.path, format_size(info.virtual_size));
+    Ok(())
+}
+
+fn convert(config: &QuickEmuConfig, disk: &Disk, format: &str, output: Option<String>) -> Result<(), Error> {
+    let from = image_info(config, &disk.path)?.format;
+    let output = output.unwrap_or_else(|| {
+        Path::new(&disk.path)
+            .with_extension(diskformat::extension_for_format(format))
+            .display().to_string()
+    });
+    if Path::new(&output).exists() {
+        return Err(Error::new(ErrorKind::QemuImg, format!("{} already exists", output)));
+    }
+    run_qemu_img(&config.qemu_img_path, &["convert", "-p", "-f", &from, "-O", format, &disk.path, &output])?;
+    println!("Converted {} to {}, point the disk's path at it to use it", disk.path, output);
+    Ok(())
+}
+
+/*
+ * Rewrite the image, which leaves out the clusters the guest freed. An
+ * overlay stays an overlay of the same base image. qemu-img convert does
+ * not copy internal snapshots, so they are only dropped when asked to.
+ */
+fn compact(config: &QuickEmuConfig, disk: &Disk, compress: bool, force: bool) -> Result<(), Error> {
+    let info = image_info(config, &disk.path)?;
+    if !info.snapshots.is_empty() && !force {
+        return Err(Error::new(ErrorKind::QemuImg, format!("{} has {} snapshot(s) that compacting would delete, use --force to delete them",
+                                                          disk.path, info.snapshots.len())));
+    }
+    let tmp = format!("{}.compact", disk.path);
+    let mut args = vec!["convert", "-f", &info.format, "-O", &info.format];
+    if compress {
+        if info.format.ne("qcow2") {
+            return Err(Error::new(ErrorKind::QemuImg, format!("Only qcow2 images can be compressed, {} is {}", disk.path, info.format)));
+        }
+        args.push("-c");
+    }
+    if let Some(backing) = &info.backing_file {
+        args.extend(["-B", backing.as_str()]);
+        if let Some(backing_format) = &info.backing_format {
+            args.extend(["-F", backing_format.as_str()]);
+        }
+    }
+    args.extend([disk.path.as_str(), tmp.as_str()]);
+    if let Err(e) = run_qemu_img(&config.qemu_img_path, &args) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&tmp, &disk.path) {
+        return Err(Error::new(ErrorKind::QemuImg, format!("Could not replace {} with {}", disk.path, tmp)).with_source(e));
+    }
+    let after = image_info(config, &disk.path)?;
+    println!("Compacted {} from {} to {}", disk.path, format_size(info.actual_size), format_size(after.actual_size));
+    Ok(())
+}
+
+//disk picks one of the vm's disks by its index, info shows them all without it
+pub fn disk_command(rt: &RuntimeDir, config: Option<&QuickEmuConfig>, action: DiskAction,
+                    disk: Option<usize>) -> Result<(), Error> {
+    let config = match config {
+        Some(c) => c,
+        None => {
+            return Err(Error::new(ErrorKind::OpenConfigFile, format!("Give the config file of {} so slquickemu knows where its disks are", rt.vmname())));
+        }
+    };
+    if config.disks.is_empty() {
+        return Err(Error::new(ErrorKind::NoSuchFile, format!("{} has no disks", config.vmname)));
+    }
+    let selected: Vec<(usize, &Disk)> = match disk {
+        Some(i) => match config.disks.get(i) {
+            Some(d) => vec![(i, d)],
+            None => {
+                return Err(Error::new(ErrorKind::NoSuchFile, format!("{} has {} disks, there is no disk {}", config.vmname, config.disks.len(), i)));
+            }
+        },
+        None => match action {
+            DiskAction::Info => config.disks.iter().enumerate().collect(),
+            _ => vec![(0, &config.disks[0])],
+        },
+    };
+    for (i, d) in selected.into_iter() {
+        if !Path::new(&d.path).exists() {
+            return Err(Error::new(ErrorKind::NoSuchFile, format!("Disk {} ({}) does not exist yet", i, d.path)));
+        }
+        match &action {
+            DiskAction::Info => print_info(config, i, d)?,
+            DiskAction::Resize(size) => {
+                check_not_in_use(config, rt, d)?;
+                resize(config, d, size)?
+            },
+            DiskAction::Convert(format, output) => {
+                check_not_in_use(config, rt, d)?;
+                convert(config, d, format, output.clone())?
+            },
+            DiskAction::Compact(compress, force) => {
+                check_not_in_use(config, rt, d)?;
+                compact(config, d, *compress, *force)?
+            },
+        }
+    }
+    Ok(())
+}
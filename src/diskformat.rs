@@ -0,0 +1,204 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * Disk image format detection from the magic bytes in the image header,
+ * using qemu's names for the formats. Anything without a known header is
+ * raw, which is also what qemu would assume.
+ */
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+//extension, qemu format. .img is left out, it is used for raw and qcow2 images alike
+const EXTENSIONS: [(&str, &str); 9] = [
+    ("qcow2", "qcow2"),
+    ("qcow", "qcow"),
+    ("qed", "qed"),
+    ("vmdk", "vmdk"),
+    ("vdi", "vdi"),
+    ("vhdx", "vhdx"),
+    ("vhd", "vpc"),
+    ("raw", "raw"),
+    ("iso", "raw"),
+];
+
+const VHD_FOOTER: u64 = 512;
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn sniff_header(header: &[u8]) -> Option<&'static str> {
+    if header.starts_with(b"QFI\xfb") {
+        //qcow version 1 is a different format to 2 and 3
+        return match header.get(4..8) {
+            Some([0, 0, 0, 1]) => Some("qcow"),
+            _ => Some("qcow2"),
+        };
+    }
+    if header.starts_with(b"QED\0") {
+        return Some("qed");
+    }
+    //sparse extent, or a text descriptor pointing at other files
+    if header.starts_with(b"KDMV") || header.starts_with(b"# Disk DescriptorFile") {
+        return Some("vmdk");
+    }
+    if header.starts_with(b"vhdxfile") {
+        return Some("vhdx");
+    }
+    //dynamic vhds have a copy of the footer at the start
+    if header.starts_with(b"conectix") {
+        return Some("vpc");
+    }
+    if header.get(0x40..0x44) == Some(&[0x7f, 0x10, 0xda, 0xbe]) {
+        return Some("vdi");
+    }
+    if header.starts_with(b"LUKS\xba\xbe") {
+        return Some("luks");
+    }
+    None
+}
+
+//formats detect_format can tell apart
+const DETECTABLE: [&str; 9] = ["qcow2", "qcow", "qed", "vmdk", "vhdx", "vpc", "vdi", "luks", "raw"];
+
+pub fn is_detectable(format: &str) -> bool {
+    DETECTABLE.contains(&format)
+}
+
+//the qemu format name of an existing image
+pub fn detect_format(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 512];
+    let len = file.read(&mut header)?;
+    if let Some(format) = sniff_header(&header[..len]) {
+        return Ok(format.to_string());
+    }
+    //fixed size vhds only have the footer
+    let size = file.metadata()?.len();
+    if size >= VHD_FOOTER {
+        let mut footer = [0u8; 8];
+        if read_at(&mut file, size - VHD_FOOTER, &mut footer)? && &footer == b"conectix" {
+            return Ok(String::from("vpc"));
+        }
+    }
+    Ok(String::from("raw"))
+}
+
+//the usual extension for images of a format
+pub fn extension_for_format(format: &str) -> &str {
+    EXTENSIONS.iter()
+        .find(|(_, f)| *f == format)
+        .map(|(e, _)| *e)
+        .unwrap_or(format)
+}
+
+//the format an image with this name is expected to have, if the extension says
+pub fn format_for_extension(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    EXTENSIONS.iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, format)| *format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn image(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("slquickemu-diskformat-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn sniff_qcow_versions() {
+        assert_eq!(sniff_header(b"QFI\xfb\0\0\0\x01"), Some("qcow"));
+        assert_eq!(sniff_header(b"QFI\xfb\0\0\0\x02"), Some("qcow2"));
+        assert_eq!(sniff_header(b"QFI\xfb\0\0\0\x03"), Some("qcow2"));
+    }
+
+    #[test]
+    fn sniff_other_headers() {
+        assert_eq!(sniff_header(b"QED\0"), Some("qed"));
+        assert_eq!(sniff_header(b"KDMV"), Some("vmdk"));
+        assert_eq!(sniff_header(b"# Disk DescriptorFile\n"), Some("vmdk"));
+        assert_eq!(sniff_header(b"vhdxfile"), Some("vhdx"));
+        assert_eq!(sniff_header(b"conectix"), Some("vpc"));
+        assert_eq!(sniff_header(b"LUKS\xba\xbe"), Some("luks"));
+        let mut vdi = [0u8; 0x44];
+        vdi[0x40..].copy_from_slice(&[0x7f, 0x10, 0xda, 0xbe]);
+        assert_eq!(sniff_header(&vdi), Some("vdi"));
+        assert_eq!(sniff_header(&[0u8; 512]), None);
+        assert_eq!(sniff_header(b""), None);
+    }
+
+    #[test]
+    fn detect_qcow_versions() {
+        let v1 = image("v1.qcow", b"QFI\xfb\0\0\0\x01");
+        let v2 = image("v2.qcow2", b"QFI\xfb\0\0\0\x02");
+        assert_eq!(detect_format(&v1).unwrap(), "qcow");
+        assert_eq!(detect_format(&v2).unwrap(), "qcow2");
+        fs::remove_file(v1).unwrap();
+        fs::remove_file(v2).unwrap();
+    }
+
+    #[test]
+    fn detect_fixed_vhd_footer() {
+        let mut contents = vec![0u8; 4096];
+        let footer = contents.len() - VHD_FOOTER as usize;
+        contents[footer..footer + 8].copy_from_slice(b"conectix");
+        let vhd = image("fixed.vhd", &contents);
+        assert_eq!(detect_format(&vhd).unwrap(), "vpc");
+        fs::remove_file(vhd).unwrap();
+    }
+
+    #[test]
+    fn detect_raw() {
+        let raw = image("plain.img", &[0u8; 4096]);
+        let tiny = image("tiny.img", b"boot");
+        assert_eq!(detect_format(&raw).unwrap(), "raw");
+        assert_eq!(detect_format(&tiny).unwrap(), "raw");
+        fs::remove_file(raw).unwrap();
+        fs::remove_file(tiny).unwrap();
+    }
+
+    #[test]
+    fn img_extension_is_ambiguous() {
+        assert_eq!(format_for_extension(Path::new("disk.img")), None);
+        assert_eq!(format_for_extension(Path::new("disk.QCOW2")), Some("qcow2"));
+        assert_eq!(format_for_extension(Path::new("disk.vhd")), Some("vpc"));
+        assert_eq!(format_for_extension(Path::new("disk")), None);
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+
+/*
+ * The error type everything returns. The kind says what went wrong and
+ * picks the exit code, the message says where, with the path or config
+ * field at fault, and the source is the io, config or qemu error under it
+ * when there is one.
+ */
+
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    OpenConfigFile,
+    ReadConfigFile,
+    NoSuchFile,
+    ScsiControllerMissing,
+    BadDiskOption,
+    MissingXdgRuntime,
+    MissingXdgConfig,
+    NoOpenPorts,
+    QemuLaunch,
+    AlreadyRunning,
+    Qmp,
+    QemuImg,
+    UnknownNetworkDevice,
+    MachineMismatch,
+    PortForward,
+    PortInUse,
+    PrivilegedPort,
+    UnknownSerialBackend,
+    MissingSmbd,
+    MissingHelper,
+    BadShare,
+    BadUsbId,
+    UnknownUsbController,
+    UnknownBootMode,
+    MissingFirmware,
+    MissingSwtpm,
+    UsbPermission,
+    TooManyDrives,
+    DiskFormat,
+    InvalidConfig,
+    Yaml,
+    Misc,
+}
+
+//process exit codes, one per kind of problem so scripts can tell them apart
+pub const EXIT_OTHER: i32 = 1;
+pub const EXIT_CONFIG_FILE: i32 = 2;
+pub const EXIT_INVALID_CONFIG: i32 = 3;
+pub const EXIT_NO_SUCH_FILE: i32 = 4;
+pub const EXIT_MISSING_DEPENDENCY: i32 = 5;
+pub const EXIT_HOST_RESOURCE: i32 = 6;
+pub const EXIT_VM_STATE: i32 = 7;
+pub const EXIT_QEMU: i32 = 8;
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        use ErrorKind::*;
+        match self {
+            OpenConfigFile | ReadConfigFile | Yaml => EXIT_CONFIG_FILE,
+            ScsiControllerMissing | BadDiskOption | UnknownNetworkDevice | MachineMismatch
+            | PortForward | UnknownSerialBackend | BadShare | BadUsbId | UnknownUsbController
+            | UnknownBootMode | TooManyDrives | DiskFormat | InvalidConfig => EXIT_INVALID_CONFIG,
+            NoSuchFile => EXIT_NO_SUCH_FILE,
+            MissingXdgRuntime | MissingXdgConfig | MissingSmbd | MissingHelper | MissingFirmware
+            | MissingSwtpm => EXIT_MISSING_DEPENDENCY,
+            NoOpenPorts | PortInUse | PrivilegedPort | UsbPermission => EXIT_HOST_RESOURCE,
+            AlreadyRunning => EXIT_VM_STATE,
+            QemuLaunch | Qmp | QemuImg => EXIT_QEMU,
+            Misc => EXIT_OTHER,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+    source: Option<Box<dyn error::Error + Send + Sync>>,
+}
+
+impl Error {
+    pub fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Error {
+        Error { kind, message: message.into(), source: None }
+    }
+
+    //the error underneath, shown after the message
+    pub fn with_source<E: Into<Box<dyn error::Error + Send + Sync>>>(mut self, source: E) -> Error {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.kind.exit_code()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{:?}", self.kind)?;
+        } else {
+            write!(f, "{}", self.message)?;
+        }
+        match &self.source {
+            Some(source) => write!(f, ": {}", source),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source.as_deref().map(|e| e as &(dyn error::Error + 'static))
+    }
+}
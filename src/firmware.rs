@@ -0,0 +1,357 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * OVMF (UEFI) firmware discovery.
+ * QEMU firmware descriptors (docs/interop/firmware.json in the qemu tree)
+ * are read first, in the same order qemu and libvirt use: the user's,
+ * then /etc, then /usr/share, the first file name wins and lower file
+ * names have priority. Distros that don't ship descriptors are covered by
+ * a list of the usual paths.
+ */
+
+use glob::Pattern;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use directories::BaseDirs;
+
+//code and vars template pairs, most specific first
+const KNOWN_OVMF: [(&str, &str); 9] = [
+    ("/usr/share/OVMF/OVMF_CODE_4M.fd", "/usr/share/OVMF/OVMF_VARS_4M.fd"),
+    ("/usr/share/OVMF/OVMF_CODE.fd", "/usr/share/OVMF/OVMF_VARS.fd"),
+    ("/usr/share/edk2/ovmf/OVMF_CODE.fd", "/usr/share/edk2/ovmf/OVMF_VARS.fd"),
+    ("/usr/share/edk2/x64/OVMF_CODE.4m.fd", "/usr/share/edk2/x64/OVMF_VARS.4m.fd"),
+    ("/usr/share/edk2-ovmf/x64/OVMF_CODE.fd", "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd"),
+    ("/usr/share/ovmf/x64/OVMF_CODE.fd", "/usr/share/ovmf/x64/OVMF_VARS.fd"),
+    ("/usr/share/qemu/ovmf-x86_64-code.bin", "/usr/share/qemu/ovmf-x86_64-vars.bin"),
+    ("/usr/share/qemu/edk2-x86_64-code.fd", "/usr/share/qemu/edk2-i386-vars.fd"),
+    ("/snap/qemu-virgil/current/usr/share/qemu/edk2-x86_64-code.fd", "/snap/qemu-virgil/current/usr/share/qemu/edk2-i386-vars.fd"),
+];
+
+//the same for secure boot, these builds want smm
+const KNOWN_SECURE_OVMF: [(&str, &str); 6] = [
+    ("/usr/share/OVMF/OVMF_CODE_4M.ms.fd", "/usr/share/OVMF/OVMF_VARS_4M.ms.fd"),
+    ("/usr/share/OVMF/OVMF_CODE_4M.secboot.fd", "/usr/share/OVMF/OVMF_VARS_4M.ms.fd"),
+    ("/usr/share/OVMF/OVMF_CODE.secboot.fd", "/usr/share/OVMF/OVMF_VARS.ms.fd"),
+    ("/usr/share/edk2/ovmf/OVMF_CODE.secboot.fd", "/usr/share/edk2/ovmf/OVMF_VARS.secboot.fd"),
+    ("/usr/share/edk2/x64/OVMF_CODE.secboot.4m.fd", "/usr/share/edk2/x64/OVMF_VARS.4m.fd"),
+    ("/usr/share/qemu/ovmf-x86_64-ms-code.bin", "/usr/share/qemu/ovmf-x86_64-ms-vars.bin"),
+];
+
+#[derive(Debug, Clone)]
+pub struct Firmware {
+    pub code: String,
+    pub vars_template: String,
+    pub format: String,
+    pub features: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FlashFile {
+    filename: String,
+    #[serde(default = "default_format")]
+    format: String,
+}
+
+fn default_format() -> String {
+    String::from("raw")
+}
+
+#[derive(Deserialize, Debug)]
+struct Mapping {
+    device: String,
+    #[serde(default)]
+    mode: Option<String>,
+    executable: Option<FlashFile>,
+    #[serde(rename = "nvram-template")]
+    nvram_template: Option<FlashFile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Target {
+    architecture: String,
+    #[serde(default)]
+    machines: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Descriptor {
+    #[serde(rename = "interface-types")]
+    interface_types: Vec<String>,
+    mapping: Mapping,
+    targets: Vec<Target>,
+    #[serde(default)]
+    features: Vec<String>,
+}
+
+fn descriptor_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(base) = BaseDirs::new() {
+        dirs.push(base.config_dir().join("qemu/firmware"));
+    }
+    dirs.push(PathBuf::from("/etc/qemu/firmware"));
+    dirs.push(PathBuf::from("/usr/share/qemu/firmware"));
+    dirs
+}
+
+//descriptor file names, a name in an earlier dir hides the same name in later ones
+fn descriptor_files(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for dir in dirs.iter() {
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".json") && !files.contains_key(&name) {
+                files.insert(name, entry.path());
+            }
+        }
+    }
+    files.into_values().collect()
+}
+
+//descriptors name versioned machine types, q35 is pc-q35-<version>
+fn machine_type(machine: &str) -> String {
+    match machine {
+        "q35" => String::from("pc-q35-latest"),
+        "pc" => String::from("pc-i440fx-latest"),
+        m => m.to_string(),
+    }
+}
+
+fn read_descriptor(path: &Path, machine: &str) -> Option<Firmware> {
+    let text = fs::read_to_string(path).ok()?;
+    let desc: Descriptor = match serde_json::from_str(&text) {
+        Ok(d) => d,
+        Err(e) => {
+            debug!("Skipping firmware descriptor {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let machine = machine_type(machine);
+    let fits = desc.targets.iter().any(|t| {
+        t.architecture == "x86_64" && t.machines.iter()
+            .any(|m| Pattern::new(m).map(|p| p.matches(&machine)).unwrap_or(false))
+    });
+    //only split flash images give us a vars file of our own
+    let split = desc.mapping.mode.as_deref().unwrap_or("split") == "split";
+    if !desc.interface_types.iter().any(|i| i == "uefi") || desc.mapping.device != "flash" || !split || !fits {
+        return None;
+    }
+    let code = desc.mapping.executable?;
+    let vars = desc.mapping.nvram_template?;
+    if !Path::new(&code.filename).exists() || !Path::new(&vars.filename).exists() {
+        debug!("Firmware descriptor {} points at missing files", path.display());
+        return None;
+    }
+    Some(Firmware {
+        code: code.filename,
+        vars_template: vars.filename,
+        format: code.format,
+        features: desc.features,
+    })
+}
+
+impl Firmware {
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    //firmware that boots anything, secure boot with enrolled keys would refuse unsigned loaders
+    fn is_plain(&self) -> bool {
+        !self.has_feature("enrolled-keys") && !self.has_feature("requires-smm")
+    }
+
+    //secure boot that is on out of the box, microsoft's keys are in the vars template
+    fn is_secure(&self) -> bool {
+        self.has_feature("secure-boot") && self.has_feature("enrolled-keys")
+    }
+}
+
+fn find_described_ovmf(dirs: &[PathBuf], machine: &str, secure_boot: bool) -> Option<Firmware> {
+    for path in descriptor_files(dirs).iter() {
+        if let Some(fw) = read_descriptor(path, machine) {
+            if (secure_boot && fw.is_secure()) || (!secure_boot && fw.is_plain()) {
+                debug!("Using firmware from {}", path.display());
+                return Some(fw);
+            }
+        }
+    }
+    None
+}
+
+pub fn find_ovmf(machine: &str, secure_boot: bool) -> Option<Firmware> {
+    if let Some(fw) = find_described_ovmf(&descriptor_dirs(), machine, secure_boot) {
+        return Some(fw);
+    }
+    known_ovmf(secure_boot).into_iter()
+        .find(|fw| Path::new(&fw.code).exists() && Path::new(&fw.vars_template).exists())
+}
+
+fn known_ovmf(secure_boot: bool) -> Vec<Firmware> {
+    let (known, features): (&[(&str, &str)], Vec<String>) = if secure_boot {
+        (&KNOWN_SECURE_OVMF, ["secure-boot", "enrolled-keys", "requires-smm"].iter().map(|f| f.to_string()).collect())
+    } else {
+        (&KNOWN_OVMF, Vec::new())
+    };
+    known.iter()
+        .map(|(code, vars)| Firmware {
+            code: code.to_string(),
+            vars_template: vars.to_string(),
+            format: String::from("raw"),
+            features: features.clone(),
+        })
+        .collect()
+}
+
+//the usual paths, for a command printed on a host without OVMF
+pub fn default_ovmf(secure_boot: bool) -> Firmware {
+    known_ovmf(secure_boot).remove(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a fresh directory of our own in the temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("slquickemu-firmware-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    //a descriptor for split flash images in dir, the images are created next to it
+    fn descriptor(dir: &Path, name: &str, machines: &[&str], features: &[&str]) -> PathBuf {
+        let code = dir.join(format!("{}_CODE.fd", name));
+        let vars = dir.join(format!("{}_VARS.fd", name));
+        fs::write(&code, "").unwrap();
+        fs::write(&vars, "").unwrap();
+        let json = serde_json::json!({
+            "description": name,
+            "interface-types": ["uefi"],
+            "mapping": {
+                "device": "flash",
+                "mode": "split",
+                "executable": { "filename": code, "format": "raw" },
+                "nvram-template": { "filename": vars, "format": "raw" }
+            },
+            "targets": [{ "architecture": "x86_64", "machines": machines }],
+            "features": features
+        });
+        let path = dir.join(format!("{}.json", name));
+        fs::write(&path, json.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_a_split_flash_descriptor() {
+        let dir = temp_dir("read");
+        let path = descriptor(&dir, "50-ovmf", &["pc-q35-*"], &["acpi-s3", "verbose-dynamic"]);
+        let fw = read_descriptor(&path, "q35").unwrap();
+        assert_eq!(fw.code, dir.join("50-ovmf_CODE.fd").display().to_string());
+        assert_eq!(fw.vars_template, dir.join("50-ovmf_VARS.fd").display().to_string());
+        assert_eq!(fw.format, "raw");
+        assert!(fw.has_feature("acpi-s3") && fw.is_plain() && !fw.is_secure());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn machine_globs() {
+        let dir = temp_dir("machines");
+        let q35 = descriptor(&dir, "q35", &["pc-q35-*"], &[]);
+        assert!(read_descriptor(&q35, "q35").is_some());
+        assert!(read_descriptor(&q35, "pc").is_none());
+        let i440fx = descriptor(&dir, "i440fx", &["pc-i440fx-*"], &[]);
+        assert!(read_descriptor(&i440fx, "pc").is_some());
+        assert!(read_descriptor(&i440fx, "q35").is_none());
+        //a versioned machine is matched as it is
+        assert!(read_descriptor(&q35, "pc-q35-8.2").is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_what_we_cant_use() {
+        let dir = temp_dir("skip");
+        let path = descriptor(&dir, "ovmf", &["pc-q35-*"], &[]);
+        let good: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let variants = [
+            ("/interface-types/0", serde_json::json!("bios")),
+            ("/mapping/device", serde_json::json!("memory")),
+            ("/mapping/mode", serde_json::json!("stateless")),
+            ("/targets/0/architecture", serde_json::json!("aarch64")),
+            ("/mapping/executable/filename", serde_json::json!(dir.join("missing.fd"))),
+        ];
+        for (pointer, value) in variants.iter() {
+            let mut json = good.clone();
+            *json.pointer_mut(pointer).unwrap() = value.clone();
+            fs::write(&path, json.to_string()).unwrap();
+            assert!(read_descriptor(&path, "q35").is_none(), "{} = {}", pointer, value);
+        }
+        fs::write(&path, "{ not json").unwrap();
+        assert!(read_descriptor(&path, "q35").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn picks_by_secure_boot() {
+        let dir = temp_dir("secure");
+        descriptor(&dir, "40-secure", &["pc-q35-*"], &["secure-boot", "enrolled-keys", "requires-smm"]);
+        descriptor(&dir, "50-plain", &["pc-q35-*", "pc-i440fx-*"], &[]);
+        let dirs = [dir.clone()];
+        let secure = find_described_ovmf(&dirs, "q35", true).unwrap();
+        assert!(secure.code.ends_with("40-secure_CODE.fd"));
+        let plain = find_described_ovmf(&dirs, "q35", false).unwrap();
+        assert!(plain.code.ends_with("50-plain_CODE.fd"));
+        assert!(find_described_ovmf(&dirs, "pc", true).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn earlier_dirs_and_lower_names_win() {
+        let user = temp_dir("user");
+        let system = temp_dir("system");
+        descriptor(&user, "60-ovmf", &["pc-q35-*"], &[]);
+        descriptor(&system, "60-ovmf", &["pc-q35-*"], &[]);
+        descriptor(&system, "70-ovmf", &["pc-q35-*"], &[]);
+        let dirs = [user.clone(), system.clone()];
+        let files = descriptor_files(&dirs);
+        assert_eq!(files, vec![user.join("60-ovmf.json"), system.join("70-ovmf.json")]);
+        let fw = find_described_ovmf(&dirs, "q35", false).unwrap();
+        assert_eq!(fw.code, user.join("60-ovmf_CODE.fd").display().to_string());
+        fs::remove_dir_all(&user).unwrap();
+        fs::remove_dir_all(&system).unwrap();
+    }
+
+    #[test]
+    fn default_is_the_first_known_pair() {
+        assert_eq!(default_ovmf(false).code, KNOWN_OVMF[0].0);
+        assert!(default_ovmf(true).is_secure());
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use crate::qemuargs::QemuCommand;
+use crate::qmp::QmpClient;
+use crate::runtime::{self, HelperPid, RuntimeDir};
+use crate::error::{Error, ErrorKind};
+
+const QMP_WAIT: Duration = Duration::from_secs(5);
+pub const HELPER_WAIT: Duration = Duration::from_secs(5);
+
+//pid of the running qemu, 0 when nothing is running
+static CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+const FORWARDED_SIGNALS: [libc::c_int; 4] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT];
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = CHILD_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+fn install_signal_forwarding() {
+    let handler = forward_signal as extern "C" fn(libc::c_int);
+    for signal in FORWARDED_SIGNALS.iter() {
+        unsafe {
+            libc::signal(*signal, handler as libc::sighandler_t);
+        }
+    }
+}
+
+fn restore_signal_handlers() {
+    for signal in FORWARDED_SIGNALS.iter() {
+        unsafe {
+            libc::signal(*signal, libc::SIG_DFL);
+        }
+    }
+}
+
+//turn a wait status into something we can hand back to the shell
+pub fn exit_code(status: &ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+//refuse to start a second copy of a vm and set up its runtime dir
+fn prepare_runtime_dir(rt: &RuntimeDir) -> Result<(), Error> {
+    if let Some(pid) = rt.running_pid() {
+        return Err(Error::new(ErrorKind::AlreadyRunning, format!("{} is already running with pid {}", rt.vmname(), pid)));
+    }
+    rt.create()?;
+    //left over when an earlier qemu died without taking them along
+    rt.stop_helpers();
+    rt.clear_state();
+    Ok(())
+}
+
+//the disk images, firmware vars and state dirs the vm needs, made before anything starts
+fn run_setup(cmd: &QemuCommand) -> Result<(), Error> {
+    for step in cmd.setup.iter() {
+        step.run()?;
+    }
+    Ok(())
+}
+
+/*
+ * pty paths are only known once qemu is up, ask it where the serial
+ * chardevs ended up and write that down for the console command.
+ */
+fn record_serial_ports(pid: u32, rt: &RuntimeDir) {
+    let start = Instant::now();
+    //qemu creates the socket during startup, don't try before it is there
+    while !rt.qmp_socket().exists() && start.elapsed() < QMP_WAIT && runtime::pid_alive(pid as i32) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut qmp = loop {
+        match QmpClient::connect(&rt.qmp_socket()) {
+            Ok(q) => break q,
+            Err(_) if start.elapsed() < QMP_WAIT && runtime::pid_alive(pid as i32) => {
+                thread::sleep(Duration::from_millis(100))
+            },
+            Err(e) => {
+                warn!("Could not ask {} where its serial ports are: {}", rt.vmname(), e);
+                return;
+            }
+        }
+    };
+    let chardevs = match qmp.query_chardev() {
+        Ok(c) => c,
+        Err(_) => return,
+    };
+    let serial: BTreeMap<String, String> = chardevs.into_iter()
+        .filter(|c| c.label.starts_with("serial"))
+        .map(|c| (c.label, c.filename))
+        .collect();
+    for (label, filename) in serial.iter() {
+        info!("{} {} is at {}", rt.vmname(), label, filename);
+    }
+    if let Err(e) = rt.write_serial(&serial) {
+        warn!("Could not record the serial ports of {}: {}", rt.vmname(), e);
+    }
+}
+
+/*
+ * qemu can still fail after it was spawned, on a missing image or a busy
+ * device, and it only takes QMP clients once the machine is set up. Wait
+ * for that so a daemonized vm that died right away is an error.
+ */
+fn wait_for_qemu(child: &mut Child, rt: &RuntimeDir) -> Result<(), Error> {
+    let start = Instant::now();
+    loop {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(Error::new(ErrorKind::QemuLaunch, format!("{} exited with status {} while starting, see {}", rt.vmname(), exit_code(&status), rt.stderr_log().display())));
+        }
+        if rt.qmp_socket().exists() && QmpClient::connect(&rt.qmp_socket()).is_ok() {
+            return Ok(());
+        }
+        if start.elapsed() > QMP_WAIT {
+            warn!("{} has not answered on {} yet", rt.vmname(), rt.qmp_socket().display());
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn open_log(path: &std::path::Path) -> Result<std::fs::File, Error> {
+    OpenOptions::new().create(true).append(true).open(path).map_err(|e| Error::new(ErrorKind::Misc, format!("Could not open log {}", path.display())).with_source(e))
+}
+
+//the recorded pids and the rest of the vm's state go with them
+fn stop_helpers(helpers: &mut [Child], rt: &RuntimeDir) {
+    for helper in helpers.iter_mut() {
+        let _ = helper.kill();
+        let _ = helper.wait();
+    }
+    rt.clear_state();
+}
+
+/*
+ * Start the processes qemu depends on, like virtiofsd, and wait for their
+ * sockets. They get their own session so a ctrl-c meant for qemu doesn't
+ * take them down first, they exit on their own once qemu disconnects.
+ * Their pids go in the runtime dir for stop and kill.
+ */
+fn spawn_helpers(cmd: &QemuCommand, rt: &RuntimeDir) -> Result<Vec<Child>, Error> {
+    let mut children = Vec::new();
+    let mut pids = BTreeMap::new();
+    for helper in cmd.helpers.iter() {
+        if let Some(socket) = &helper.socket {
+            //a socket left over from an earlier run would look like the helper is already up
+            let _ = std::fs::remove_file(socket);
+        }
+        info!("Launching {}", helper.to_shell_string());
+        let log = rt.path.join(format!("{}.log", helper.name));
+        let stdout = open_log(&log)?;
+        let stderr = open_log(&log)?;
+        let mut command = Command::new(&helper.binary);
+        command.args(&helper.args)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr);
+        unsafe {
+            command.pre_exec(|| {
+                libc::setsid();
+                Ok(())
+            });
+        }
+        let mut child = match command.spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                stop_helpers(&mut children, rt);
+                return Err(Error::new(ErrorKind::MissingHelper, format!("Failed to launch {}", helper.binary)).with_source(e));
+            }
+        };
+        if let Some(socket) = &helper.socket {
+            let start = Instant::now();
+            while !std::path::Path::new(socket).exists() {
+                let exited = matches!(child.try_wait(), Ok(Some(_)));
+                if exited || start.elapsed() > HELPER_WAIT {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    stop_helpers(&mut children, rt);
+                    return Err(Error::new(ErrorKind::MissingHelper, format!("{} did not come up, see {}", helper.name, log.display())));
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+        pids.insert(helper.name.clone(), HelperPid { pid: child.id(), binary: helper.binary.clone() });
+        children.push(child);
+        if let Err(e) = rt.write_helpers(&pids) {
+            stop_helpers(&mut children, rt);
+            return Err(e);
+        }
+    }
+    Ok(children)
+}
+
+fn has_serial(cmd: &QemuCommand) -> bool {
+    cmd.find("serial").is_some()
+}
+
+//start qemu in its own session with output going to the runtime dir logs
+pub fn spawn_daemon(cmd: &QemuCommand, rt: &RuntimeDir) -> Result<u32, Error> {
+    if cmd.args.iter().any(|a| a.name == "chardev" && a.value.as_deref() == Some("stdio")) {
+        return Err(Error::new(ErrorKind::UnknownSerialBackend, "A serial port on stdio can't be used with --daemonize"));
+    }
+    prepare_runtime_dir(rt)?;
+    run_setup(cmd)?;
+    let stdout = open_log(&rt.stdout_log())?;
+    let stderr = open_log(&rt.stderr_log())?;
+
+    info!("Launching {} in the background", cmd.to_shell_string());
+    let mut command = Command::new(&cmd.binary);
+    command.args(cmd.args())
+        .envs(&cmd.env)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr);
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    let mut helpers = spawn_helpers(cmd, rt)?;
+    let mut child = match command.spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            stop_helpers(&mut helpers, rt);
+            return Err(Error::new(ErrorKind::QemuLaunch, format!("Failed to launch {}", cmd.binary)).with_source(e));
+        }
+    };
+    let started = rt.write_pid(child.id(), &cmd.binary)
+        .and_then(|_| rt.write_ports(&cmd.ports))
+        .and_then(|_| wait_for_qemu(&mut child, rt));
+    if let Err(e) = started {
+        let _ = child.kill();
+        let _ = child.wait();
+        stop_helpers(&mut helpers, rt);
+        return Err(e);
+    }
+    if has_serial(cmd) {
+        record_serial_ports(child.id(), rt);
+    }
+    Ok(child.id())
+}
+
+pub fn run_qemu(cmd: &QemuCommand, rt: &RuntimeDir) -> Result<i32, Error> {
+    prepare_runtime_dir(rt)?;
+    run_setup(cmd)?;
+    let qemu_path = cmd.binary.as_str();
+    let mut helpers = spawn_helpers(cmd, rt)?;
+    info!("Launching {}", cmd.to_shell_string());
+    let mut child = match Command::new(qemu_path).args(cmd.args()).envs(&cmd.env).spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            stop_helpers(&mut helpers, rt);
+            return Err(Error::new(ErrorKind::QemuLaunch, format!("Failed to launch {}", qemu_path)).with_source(e));
+        }
+    };
+
+    //signals sent to us (ctrl-c, kill, logout) go to qemu so it can shut down cleanly
+    CHILD_PID.store(child.id() as i32, Ordering::SeqCst);
+    install_signal_forwarding();
+    let recorded = rt.write_pid(child.id(), qemu_path).and_then(|_| rt.write_ports(&cmd.ports));
+    if let Err(e) = recorded {
+        warn!("Other commands will not be able to find {}: {}", rt.vmname(), e);
+    }
+    if has_serial(cmd) {
+        record_serial_ports(child.id(), rt);
+    }
+
+    let status = child.wait();
+
+    restore_signal_handlers();
+    CHILD_PID.store(0, Ordering::SeqCst);
+    stop_helpers(&mut helpers, rt);
+
+    match status {
+        Ok(s) => {
+            debug!("qemu exited with {}", s);
+            Ok(exit_code(&s))
+        },
+        Err(e) => {
+            Err(Error::new(ErrorKind::QemuLaunch, format!("Failed waiting on {}", qemu_path)).with_source(e))
+        }
+    }
+}
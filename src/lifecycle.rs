@@ -0,0 +1,220 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::qemuconfig;
+use crate::qmp::QmpClient;
+use crate::runtime::{self, RuntimeDir};
+use crate::error::{Error, ErrorKind};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const FORCE_QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+ * A vm can be named by its vmname or by the path of its config file,
+ * in which case the config is loaded to find the vmname.
+ */
+pub fn resolve_vm(target: &str) -> Result<RuntimeDir, Error> {
+    Ok(resolve_vm_config(target)?.0)
+}
+
+//as resolve_vm, also handing back the config when we were given one
+pub fn resolve_vm_config(target: &str) -> Result<(RuntimeDir, Option<qemuconfig::QuickEmuConfig>), Error> {
+    let path = Path::new(target);
+    if path.is_file() {
+        let config = qemuconfig::setup_options(target)?;
+        debug!("{} is the config for {}", target, config.vmname);
+        Ok((RuntimeDir::for_vm(&config.vmname)?, Some(config)))
+    } else {
+        Ok((RuntimeDir::for_vm(target)?, None))
+    }
+}
+
+fn wait_for_exit(pid: i32, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if !runtime::pid_alive(pid) {
+            return true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    !runtime::pid_alive(pid)
+}
+
+fn send_signal(pid: i32, signal: libc::c_int) {
+    unsafe {
+        libc::kill(pid, signal);
+    }
+}
+
+//last resort when qmp is gone or qemu ignores it
+fn terminate(pid: i32) -> bool {
+    send_signal(pid, libc::SIGTERM);
+    if wait_for_exit(pid, FORCE_QUIT_TIMEOUT) {
+        return true;
+    }
+    warn!("pid {} ignored SIGTERM, sending SIGKILL", pid);
+    send_signal(pid, libc::SIGKILL);
+    wait_for_exit(pid, FORCE_QUIT_TIMEOUT)
+}
+
+//acpi powerdown, then qmp quit once the timeout runs out, then signals
+pub fn stop_vm(rt: &RuntimeDir, timeout: Duration) -> Result<(), Error> {
+    let pid = match rt.running_pid() {
+        Some(pid) => pid,
+        None => {
+            println!("{} is not running", rt.vmname());
+            rt.stop_helpers();
+            rt.clear_state();
+            return Ok(());
+        }
+    };
+
+    let mut stopped = false;
+    match QmpClient::connect(&rt.qmp_socket()) {
+        Ok(mut qmp) => {
+            println!("Sending ACPI powerdown to {}", rt.vmname());
+            //a vm that can't take the powerdown still has to stop
+            if let Err(e) = qmp.system_powerdown() {
+                warn!("Could not send ACPI powerdown to {}, falling back to signals: {}", rt.vmname(), e);
+            } else {
+                let start = Instant::now();
+                let mut closed = false;
+                while start.elapsed() < timeout && runtime::pid_alive(pid) {
+                    match qmp.next_event(POLL_INTERVAL) {
+                        Ok(Some(ev)) => debug!("{} event {} at {}.{:06} {}", rt.vmname(), ev.event,
+                                               ev.timestamp.seconds, ev.timestamp.microseconds, ev.data),
+                        Ok(None) => (),
+                        //qemu closing the socket is how a clean shutdown usually looks
+                        Err(_) => {
+                            closed = true;
+                            break;
+                        },
+                    }
+                }
+                stopped = if closed {
+                    wait_for_exit(pid, FORCE_QUIT_TIMEOUT)
+                } else {
+                    !runtime::pid_alive(pid)
+                };
+                if !stopped {
+                    warn!("{} did not power down within {}s, forcing quit", rt.vmname(), timeout.as_secs());
+                    if qmp.quit().is_ok() {
+                        stopped = wait_for_exit(pid, FORCE_QUIT_TIMEOUT);
+                    }
+                }
+            }
+        },
+        Err(e) => warn!("No QMP connection to {}, falling back to signals: {}", rt.vmname(), e),
+    }
+
+    if !stopped && !terminate(pid) {
+        return Err(Error::new(ErrorKind::Misc, format!("Could not stop {} (pid {})", rt.vmname(), pid)));
+    }
+    rt.stop_helpers();
+    rt.clear_state();
+    println!("{} stopped", rt.vmname());
+    Ok(())
+}
+
+pub fn kill_vm(rt: &RuntimeDir) -> Result<(), Error> {
+    match rt.running_pid() {
+        Some(pid) => {
+            if !terminate(pid) {
+                return Err(Error::new(ErrorKind::Misc, format!("Could not kill {} (pid {})", rt.vmname(), pid)));
+            }
+            println!("{} killed", rt.vmname());
+        },
+        None => println!("{} is not running", rt.vmname()),
+    }
+    rt.stop_helpers();
+    rt.clear_state();
+    Ok(())
+}
+
+//running, paused or stopped; whatever qemu says if it is something else
+pub fn vm_state(rt: &RuntimeDir) -> String {
+    if rt.running_pid().is_none() {
+        return String::from("stopped");
+    }
+    let status = QmpClient::connect(&rt.qmp_socket())
+        .and_then(|mut qmp| qmp.query_status());
+    match status {
+        Ok(s) if s.running => String::from("running"),
+        Ok(s) if s.status == "paused" => String::from("paused"),
+        Ok(s) => s.status,
+        Err(_) => String::from("running (no qmp)"),
+    }
+}
+
+pub fn status_vm(rt: &RuntimeDir) -> Result<(), Error> {
+    let state = vm_state(rt);
+    println!("{}: {}", rt.vmname(), state);
+    let pid = match rt.running_pid() {
+        Some(pid) => pid,
+        None => {
+            rt.clear_state();
+            return Ok(());
+        }
+    };
+    println!("  pid: {}", pid);
+    for (name, port) in rt.read_ports().iter() {
+        println!("  {} port: {}", name, port);
+    }
+    if let Ok(blocks) = QmpClient::connect(&rt.qmp_socket()).and_then(|mut qmp| qmp.query_block()) {
+        for b in blocks.iter() {
+            let name = if b.qdev.is_empty() { &b.device } else { &b.qdev };
+            let flags = format!("{}{}", if b.removable { " removable" } else { "" },
+                                if b.locked { " locked" } else { "" });
+            match &b.inserted {
+                Some(media) => println!("  {}: {} ({}{}){}", name, media.file, media.drv,
+                                        if media.ro { ", ro" } else { "" }, flags),
+                None => println!("  {}: empty{}", name, flags),
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn list_vms() -> Result<(), Error> {
+    println!("{:<24} {:<18} {:>8}  PORTS", "NAME", "STATE", "PID");
+    for rt in runtime::list_runtime_dirs()?.iter() {
+        let running = rt.running_pid();
+        let pid = running
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| String::from("-"));
+        //a vm that stopped on its own leaves the ports of its last run behind
+        let ports: Vec<String> = match running {
+            Some(_) => rt.read_ports().iter()
+                .map(|(name, port)| format!("{}={}", name, port))
+                .collect(),
+            None => Vec::new(),
+        };
+        println!("{:<24} {:<18} {:>8}  {}", rt.vmname(), vm_state(rt), pid, ports.join(","));
+    }
+    Ok(())
+}
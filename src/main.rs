@@ -24,22 +24,98 @@
  */
 
 pub mod qemuconfig;
+mod qemuargs;
 mod utils;
-use env_logger::Env;
+mod launcher;
+mod output;
+mod runtime;
+mod qmp;
+mod lifecycle;
+mod snapshot;
+mod console;
+mod usb;
+mod firmware;
+mod diskformat;
+mod clone;
+mod disk;
+mod validate;
+mod error;
+mod configenum;
+mod configkeys;
 extern crate clap;
-use clap::{Arg, App }; // SubCommand, Values};
-//use std::process::Command;
-use crate::qemuconfig::ERRORCODES;
-use log::LevelFilter;
+use clap::{Arg, App, ArgMatches, SubCommand};
+use crate::error::{Error, ErrorKind};
 extern crate pretty_env_logger;
 extern crate env_logger;
 #[macro_use] extern crate log;
 
-use env_logger::Target;
 use std::env;
+use std::process;
+use std::time::Duration;
 
-#[allow(unused_variables)]
-fn main() -> Result<(), ERRORCODES> {
+fn vm_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("vm")
+        .value_name("VM")
+        .help("vmname or config file of the vm")
+        .required(true)
+}
+
+fn snapshot_name_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("name")
+        .value_name("NAME")
+        .help("snapshot name")
+        .required(true)
+}
+
+fn disk_index_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("disk")
+        .long("disk")
+        .value_name("N")
+        .help("which of the vm's disks, counting from 0")
+        .takes_value(true)
+}
+
+fn strict_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("strict")
+        .long("strict")
+        .help("Treat unknown keys in the config and tweak files as errors")
+}
+
+//options shared by the bare invocation and the start subcommand
+fn launch_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("config")
+            .long("vm")
+            .value_name("CONFIG")
+            .help("Config File to Run")
+            .takes_value(true),
+        Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Print the qemu command line instead of running it"),
+        Arg::with_name("print-json")
+            .long("print-json")
+            .conflicts_with_all(&["dry-run", "print-shell"])
+            .help("Print the invocation and merged config as JSON instead of running it"),
+        Arg::with_name("print-shell")
+            .long("print-shell")
+            .conflicts_with("dry-run")
+            .help("Print a shell script that runs the vm instead of running it"),
+        Arg::with_name("daemonize")
+            .long("daemonize")
+            .help("Run qemu in the background, logging to the vm's runtime dir"),
+        strict_arg(),
+    ]
+}
+
+//errors are logged once here and picked the exit code, see Exit codes in the README
+fn main() {
+    if let Err(e) = run() {
+        error!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+fn run() -> Result<(), Error> {
     let matches = App::new("slquickemu")
         .version("0.1")
         .author("HC hc@hackerlan.com")
@@ -47,71 +123,280 @@ fn main() -> Result<(), ERRORCODES> {
         \nBased on quickemu by Martin Wimpress\
         \nGPL Version 2\nNo Warranty!\n
         ")
-        .arg(Arg::with_name("config")
-            .long("vm")
-            .value_name("CONFIG")
-            .help("Config File to Run")
-            .takes_value(true)
-            .required(true)
-        )
+        .args(&launch_args())
         .arg( Arg::with_name("v")
             .short("v")
             .multiple(true)
             .required(false)
+            .global(true)
             .help("sets the level of verbosity (RUST_LOG=)")
         )
+        .subcommand(SubCommand::with_name("start")
+            .about("Start a vm")
+            .args(&launch_args())
+        )
+        .subcommand(SubCommand::with_name("stop")
+            .about("ACPI power down a vm, force quitting it after the timeout")
+            .arg(vm_arg())
+            .arg(Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .default_value("60")
+                .help("How long to wait for the guest to power down")
+            )
+        )
+        .subcommand(SubCommand::with_name("status")
+            .about("Show whether a vm is running, paused or stopped")
+            .arg(vm_arg())
+        )
+        .subcommand(SubCommand::with_name("kill")
+            .about("End a vm's qemu process")
+            .arg(vm_arg())
+        )
+        .subcommand(SubCommand::with_name("list")
+            .about("List known vms with their pid and ports")
+        )
+        .subcommand(SubCommand::with_name("console")
+            .about("Attach the terminal to the first serial port of a running vm, ctrl-] detaches")
+            .arg(vm_arg())
+        )
+        .subcommand(SubCommand::with_name("snapshot")
+            .about("Manage named snapshots, live through the monitor or offline with qemu-img")
+            .subcommand(SubCommand::with_name("create")
+                .about("Save a snapshot")
+                .arg(vm_arg())
+                .arg(snapshot_name_arg())
+            )
+            .subcommand(SubCommand::with_name("list")
+                .about("Show snapshots with their size, date and vm clock")
+                .arg(vm_arg())
+            )
+            .subcommand(SubCommand::with_name("apply")
+                .about("Restore a snapshot")
+                .arg(vm_arg())
+                .arg(snapshot_name_arg())
+            )
+            .subcommand(SubCommand::with_name("delete")
+                .about("Delete a snapshot")
+                .arg(vm_arg())
+                .arg(snapshot_name_arg())
+            )
+        )
+        .subcommand(SubCommand::with_name("check")
+            .about("Report every error and warning in a vm config without starting it")
+            .arg(Arg::with_name("config")
+                .long("vm")
+                .value_name("CONFIG")
+                .help("Config File to Check")
+                .required(true)
+                .takes_value(true))
+            .arg(strict_arg())
+        )
+        .subcommand(SubCommand::with_name("clone")
+            .about("Write a config for a new vm whose disks are overlays of another vm's")
+            .arg(Arg::with_name("src")
+                .value_name("SRC")
+                .help("config file of the vm to clone")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("dst")
+                .value_name("DST")
+                .help("config file to write for the clone, its name is the new vmname")
+                .required(true)
+                .index(2))
+        )
+        .subcommand(SubCommand::with_name("disk")
+            .about("Disk maintenance with qemu-img, refused while the vm runs")
+            .subcommand(SubCommand::with_name("info")
+                .about("Show format, size and base image of the vm's disks")
+                .arg(vm_arg())
+                .arg(disk_index_arg())
+            )
+            .subcommand(SubCommand::with_name("resize")
+                .about("Grow a disk, e.g. to 200G or by +20G")
+                .arg(vm_arg())
+                .arg(Arg::with_name("size")
+                    .value_name("SIZE")
+                    .required(true))
+                .arg(disk_index_arg())
+            )
+            .subcommand(SubCommand::with_name("convert")
+                .about("Write a copy of a disk in another format")
+                .arg(vm_arg())
+                .arg(Arg::with_name("format")
+                    .value_name("FORMAT")
+                    .help("qcow2, raw, vmdk, vdi, vhdx or vpc")
+                    .required(true))
+                .arg(disk_index_arg())
+                .arg(Arg::with_name("output")
+                    .long("output")
+                    .short("o")
+                    .value_name("PATH")
+                    .help("where to write the copy, defaults to the disk with the format's extension")
+                    .takes_value(true))
+            )
+            .subcommand(SubCommand::with_name("compact")
+                .about("Rewrite a disk to give the space the guest freed back to the host")
+                .arg(vm_arg())
+                .arg(disk_index_arg())
+                .arg(Arg::with_name("compress")
+                    .long("compress")
+                    .help("compress the rewritten qcow2 image"))
+                .arg(Arg::with_name("force")
+                    .long("force")
+                    .help("compact even though it deletes the image's snapshots"))
+            )
+        )
+        .subcommand(SubCommand::with_name("usb")
+            .about("USB host devices that can be passed through")
+            .subcommand(SubCommand::with_name("list")
+                .about("List the host's USB devices and whether you can pass them through")
+                .arg(Arg::with_name("sysfs-root")
+                    .long("sysfs-root")
+                    .value_name("DIR")
+                    .help("Read devices from DIR/bus/usb/devices instead of /sys")
+                    .takes_value(true))
+            )
+        )
         .get_matches();
-/*
-    let r = Command::new("/bin/ls")
-        .args(&["-ltra","/"])
-        .output()
-        .expect("Failed to run LS");
-    println!("e {}",r.status);
-    println!("stdout: {}", String::from_utf8_lossy(&r.stdout));
-    println!("stdout: {}", String::from_utf8_lossy(&r.stderr));
-*/
     match matches.occurrences_of("v")
     {
         0 => env::set_var("RUST_LOG","error"),
         1 => env::set_var("RUST_LOG","warn"),
         2 => env::set_var("RUST_LOG","info"),
         3 => env::set_var("RUST_LOG","debug"),
-        4 | _ => env::set_var("RUST_LOG", "trace"),
+        _ => env::set_var("RUST_LOG", "trace"),
     }
 
     pretty_env_logger::init_custom_env("RUST_LOG");
-    let config = matches.value_of("config").unwrap();
-    info!("Using config file: {}",config);
-
 
-    let quick_emu_config = qemuconfig::setup_options(&config);
-    let (cfg,config) =
-    match quick_emu_config {
-        Ok(config) => {
-            match qemuconfig::build_config(&config) {
-                Ok(t) => (t,config),
-                Err(e) => return Err(e),
-            }
+    match matches.subcommand() {
+        ("start", Some(sub)) => start_vm(sub),
+        ("stop", Some(sub)) => {
+            let timeout = match sub.value_of("timeout").unwrap_or("60").parse() {
+                Ok(t) => Duration::from_secs(t),
+                Err(_) => {
+                    return Err(Error::new(ErrorKind::Misc, "--timeout must be a number of seconds"));
+                }
+            };
+            lifecycle::stop_vm(&lifecycle::resolve_vm(sub.value_of("vm").unwrap())?, timeout)
         },
-        Err(e) => {
-            error!("Error loading config");
-            return Err(e);
+        ("status", Some(sub)) => lifecycle::status_vm(&lifecycle::resolve_vm(sub.value_of("vm").unwrap())?),
+        ("kill", Some(sub)) => lifecycle::kill_vm(&lifecycle::resolve_vm(sub.value_of("vm").unwrap())?),
+        ("list", Some(_)) => lifecycle::list_vms(),
+        ("snapshot", Some(sub)) => snapshot_command(sub),
+        ("console", Some(sub)) => console::attach_console(&lifecycle::resolve_vm(sub.value_of("vm").unwrap())?),
+        ("usb", Some(sub)) => usb_command(sub),
+        ("disk", Some(sub)) => disk_command(sub),
+        ("check", Some(sub)) => validate::check_config(sub.value_of("config").unwrap(), sub.is_present("strict")),
+        ("clone", Some(sub)) => clone::clone_vm(sub.value_of("src").unwrap(), sub.value_of("dst").unwrap()),
+        _ => start_vm(&matches),
+    }
+}
+
+fn disk_command(matches: &ArgMatches) -> Result<(), Error> {
+    let (action, sub) = match matches.subcommand() {
+        ("info", Some(sub)) => (disk::DiskAction::Info, sub),
+        ("resize", Some(sub)) => (disk::DiskAction::Resize(sub.value_of("size").unwrap().to_string()), sub),
+        ("convert", Some(sub)) => (disk::DiskAction::Convert(sub.value_of("format").unwrap().to_string(),
+                                                               sub.value_of("output").map(String::from)), sub),
+        ("compact", Some(sub)) => (disk::DiskAction::Compact(sub.is_present("compress"), sub.is_present("force")), sub),
+        _ => {
+            return Err(Error::new(ErrorKind::Misc, "disk needs one of info, resize, convert or compact"));
+        }
+    };
+    let index = match sub.value_of("disk").map(|d| d.parse::<usize>()) {
+        Some(Ok(i)) => Some(i),
+        Some(Err(_)) => {
+            return Err(Error::new(ErrorKind::Misc, "--disk must be the number of a disk, starting at 0"));
         },
+        None => None,
     };
+    let (rt, config) = lifecycle::resolve_vm_config(sub.value_of("vm").unwrap())?;
+    disk::disk_command(&rt, config.as_ref(), action, index)
+}
 
-    //let mut arguments = Vec::new();
-    print!("qemu-system-i386 ");
-    for test in cfg {
-      //println!("{} ",test);
-        let s: Vec<String> = test.trim().split(' ').map(|t|t.to_string()).collect();
-        for f in s {
-            print!("{} ", f);
+fn usb_command(matches: &ArgMatches) -> Result<(), Error> {
+    match matches.subcommand() {
+        ("list", Some(sub)) => {
+            let root = match sub.value_of("sysfs-root") {
+                Some(r) => std::path::PathBuf::from(r),
+                None => usb::sysfs_root(),
+            };
+            usb::print_devices(&root)
+        },
+        _ => {
+            Err(Error::new(ErrorKind::Misc, "usb needs a subcommand, try usb list"))
         }
     }
-    Ok(())
-    //for m in arguments {
-    //    print!("{} ",m);
-    //}
+}
 
+fn snapshot_command(matches: &ArgMatches) -> Result<(), Error> {
+    let (action, sub) = match matches.subcommand() {
+        ("create", Some(sub)) => (snapshot::SnapshotAction::Create, sub),
+        ("list", Some(sub)) => (snapshot::SnapshotAction::List, sub),
+        ("apply", Some(sub)) => (snapshot::SnapshotAction::Apply, sub),
+        ("delete", Some(sub)) => (snapshot::SnapshotAction::Delete, sub),
+        _ => {
+            return Err(Error::new(ErrorKind::Misc, "snapshot needs one of create, list, apply or delete"));
+        }
+    };
+    let (rt, config) = lifecycle::resolve_vm_config(sub.value_of("vm").unwrap())?;
+    snapshot::snapshot_vm(&rt, config.as_ref(), action, sub.value_of("name").unwrap_or(""))
 }
 
+fn start_vm(matches: &ArgMatches) -> Result<(), Error> {
+    let config = match matches.value_of("config") {
+        Some(c) => c,
+        None => {
+            return Err(Error::new(ErrorKind::OpenConfigFile, "No vm config given, use --vm CONFIG"));
+        }
+    };
+    info!("Using config file: {}",config);
+
+
+    let config = qemuconfig::setup_options(config)?;
+    validate::report_unknown_keys(&config, matches.is_present("strict"))?;
+    //the print modes may be run later or on another host, so the host checks are only for a real launch
+    let printing = ["dry-run", "print-json", "print-shell"].iter().any(|a| matches.is_present(a));
+    if !printing {
+        validate::validate_for_start(&config)?;
+    }
+    let cfg = qemuconfig::build_config(&config)?;
+
+    if matches.is_present("dry-run") {
+        for step in cfg.setup.iter() {
+            println!("{}", step.to_shell_string());
+        }
+        for helper in cfg.helpers.iter() {
+            println!("{}", helper.to_shell_string());
+        }
+        println!("{}", cfg.to_shell_string());
+        return Ok(());
+    }
+    if matches.is_present("print-json") {
+        println!("{}", output::to_json(&cfg, &config)?);
+        return Ok(());
+    }
+    if matches.is_present("print-shell") {
+        print!("{}", output::to_shell_script(&cfg, &config)?);
+        return Ok(());
+    }
+
+    if !config.shared_folder.is_empty() {
+        println!("{} is shared with the guest as {}", config.shared_folder, qemuconfig::SMB_UNC_PATH);
+    }
+
+    let rt = runtime::RuntimeDir::for_vm(&config.vmname)?;
+    if matches.is_present("daemonize") {
+        let pid = launcher::spawn_daemon(&cfg, &rt)?;
+        println!("{} started with pid {}, runtime dir {}", config.vmname, pid, rt.path.display());
+        return Ok(());
+    }
+
+    let code = launcher::run_qemu(&cfg, &rt)?;
+    if code != 0 {
+        warn!("qemu exited with status {}", code);
+    }
+    process::exit(code);
+}
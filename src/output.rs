@@ -0,0 +1,105 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use crate::qemuargs::{HelperProcess, QemuCommand, SetupStep, shell_quote};
+use crate::qemuconfig;
+use crate::runtime::RuntimeDir;
+use crate::error::{Error, ErrorKind};
+use crate::launcher::HELPER_WAIT;
+
+//everything a wrapper needs to know about an invocation, for --print-json
+#[derive(Serialize)]
+struct Invocation<'a> {
+    binary: &'a str,
+    argv: Vec<String>,
+    env: &'a BTreeMap<String, String>,
+    ports: &'a BTreeMap<String, u16>,
+    helpers: &'a Vec<HelperProcess>,
+    setup: &'a Vec<SetupStep>,
+    config: &'a qemuconfig::QuickEmuConfig,
+}
+
+pub fn to_json(cmd: &QemuCommand, config: &qemuconfig::QuickEmuConfig) -> Result<String, Error> {
+    let invocation = Invocation {
+        binary: &cmd.binary,
+        argv: cmd.to_argv(),
+        env: &cmd.env,
+        ports: &cmd.ports,
+        helpers: &cmd.helpers,
+        setup: &cmd.setup,
+        config,
+    };
+    match serde_json::to_string_pretty(&invocation) {
+        Ok(s) => Ok(s),
+        Err(e) => {
+            Err(Error::new(ErrorKind::Misc, "Failed to serialize invocation").with_source(e))
+        }
+    }
+}
+
+//give up when the helper dies or its socket doesn't show up in time, like the launcher does
+fn wait_for_helper(helper: &HelperProcess, socket: &str) -> String {
+    let exited = shell_quote(&format!("{} exited before creating {}", helper.name, socket));
+    let timeout = shell_quote(&format!("{} did not create {} in {}s", helper.name, socket, HELPER_WAIT.as_secs()));
+    let mut lines = String::from("helper_pid=$!\nwaited=0\n");
+    lines += &format!("while [ ! -S {} ]; do\n", shell_quote(socket));
+    lines += &format!("    kill -0 $helper_pid 2>/dev/null || {{ echo {} >&2; exit 1; }}\n", exited);
+    lines += &format!("    [ $waited -lt {} ] || {{ echo {} >&2; kill $helper_pid; exit 1; }}\n", HELPER_WAIT.as_secs(), timeout);
+    lines += "    sleep 1\n    waited=$((waited + 1))\ndone\n";
+    lines
+}
+
+//a posix sh script that runs the vm later exactly as we would have
+pub fn to_shell_script(cmd: &QemuCommand, config: &qemuconfig::QuickEmuConfig) -> Result<String, Error> {
+    let mut script = String::from("#!/bin/sh\nset -e\n");
+    script += &format!("# generated by {} for vm {}\n", config.launcher, config.vmname);
+    for (name, port) in cmd.ports.iter() {
+        script += &format!("# {} port: {}\n", name, port);
+    }
+    for (key, value) in cmd.env.iter() {
+        script += &format!("export {}={}\n", key, shell_quote(value));
+    }
+    //the qmp and helper sockets live in the runtime dir, which may be gone by the time this runs
+    let rt = RuntimeDir::for_vm(&config.vmname)?;
+    script += &format!("mkdir -p {}\n", shell_quote(&rt.path.display().to_string()));
+    for step in cmd.setup.iter() {
+        script += &format!("{}\n", step.to_shell_string());
+    }
+    for helper in cmd.helpers.iter() {
+        script += &format!("{} &\n", helper.to_shell_string());
+        if let Some(socket) = &helper.socket {
+            script += &wait_for_helper(helper, socket);
+        }
+    }
+    script += &format!("exec {}", shell_quote(&cmd.binary));
+    for arg in cmd.args.iter() {
+        let words: Vec<String> = arg.to_argv().iter().map(|w| shell_quote(w)).collect();
+        script += &format!(" \\\n    {}", words.join(" "));
+    }
+    script.push('\n');
+    Ok(script)
+}
@@ -0,0 +1,387 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use crate::error::{Error, ErrorKind};
+use crate::utils::run_qemu_img;
+
+/*
+ * A single qemu option such as
+ *   -drive if=none,id=drive0,file=/some/path
+ * name is the option without the leading dash, value is the optional
+ * leading unnamed value (the driver for -device, the backend for -audiodev)
+ * and props are the key=value pairs that follow it.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QemuArg {
+    pub name: String,
+    pub value: Option<String>,
+    pub props: Vec<(String, Option<String>)>,
+}
+
+//a process qemu needs running before it starts, e.g. virtiofsd
+#[derive(Debug, Clone, Serialize)]
+pub struct HelperProcess {
+    pub name: String,
+    pub binary: String,
+    pub args: Vec<String>,
+    //qemu connects here, launching waits for it to show up
+    pub socket: Option<String>,
+}
+
+/*
+ * Something on the host that has to be in place before qemu starts, like
+ * a disk image that doesn't exist yet. Building the command only writes
+ * these down, the launcher carries them out, the print modes show them.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "step", rename_all = "kebab-case")]
+pub enum SetupStep {
+    //qemu-img create, for an image that is missing
+    CreateImage { path: String, argv: Vec<String> },
+    //a writable copy of a read only template, made once
+    CopyFile { from: String, to: String },
+    CreateDir { path: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QemuCommand {
+    pub binary: String,
+    pub args: Vec<QemuArg>,
+    //extra environment for the qemu process
+    pub env: BTreeMap<String, String>,
+    //host ports handed out while building the command, e.g. spice
+    pub ports: BTreeMap<String, u16>,
+    pub helpers: Vec<HelperProcess>,
+    pub setup: Vec<SetupStep>,
+}
+
+//qemu option lists use ',' as a separator, a literal comma is written as ',,'
+pub fn escape_value(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+//split an option list on single commas, leaving ',,' as a literal comma
+fn split_option_list(text: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ',' {
+            if chars.peek() == Some(&',') {
+                chars.next();
+                current.push(',');
+            } else {
+                parts.push(current);
+                current = String::new();
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    parts.push(current);
+    parts
+}
+
+pub fn shell_quote(word: &str) -> String {
+    let safe = !word.is_empty() && word.chars().all(|c| {
+        c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c)
+    });
+    if safe {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+impl QemuArg {
+    //an option that takes nothing, e.g. -enable-kvm
+    pub fn flag(name: &str) -> QemuArg {
+        QemuArg {
+            name: name.to_string(),
+            value: None,
+            props: Vec::new(),
+        }
+    }
+
+    pub fn with_value(name: &str, value: &str) -> QemuArg {
+        QemuArg {
+            name: name.to_string(),
+            value: Some(value.to_string()),
+            props: Vec::new(),
+        }
+    }
+
+    /*
+     * Build an option from text that is already in qemu syntax,
+     * used for user supplied strings like cpu = "host,kvm=on".
+     * The first element without an '=' becomes the value.
+     */
+    pub fn parse(name: &str, text: &str) -> QemuArg {
+        let mut arg = QemuArg::flag(name);
+        arg.append_props(text);
+        arg
+    }
+
+    pub fn prop(mut self, key: &str, value: &str) -> QemuArg {
+        self.props.push((key.to_string(), Some(value.to_string())));
+        self
+    }
+
+    //a bare key without a value, e.g. disable-ticketing
+    pub fn bare_prop(mut self, key: &str) -> QemuArg {
+        self.props.push((key.to_string(), None));
+        self
+    }
+
+    pub fn append_props(&mut self, text: &str) {
+        for (i, part) in split_option_list(text).into_iter().enumerate() {
+            if part.is_empty() {
+                continue;
+            }
+            match part.find('=') {
+                Some(pos) => self.props.push((part[..pos].to_string(), Some(part[pos + 1..].to_string()))),
+                None => {
+                    if i == 0 && self.value.is_none() && self.props.is_empty() {
+                        self.value = Some(part);
+                    } else {
+                        self.props.push((part, None));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get_prop(&self, key: &str) -> Option<&str> {
+        self.props.iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    pub fn option(&self) -> String {
+        format!("-{}", self.name)
+    }
+
+    //the option list passed as the argument after the option, if any
+    pub fn option_list(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(v) = &self.value {
+            parts.push(escape_value(v));
+        }
+        for (k, v) in self.props.iter() {
+            match v {
+                Some(v) => parts.push(format!("{}={}", k, escape_value(v))),
+                None => parts.push(k.clone()),
+            }
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(","))
+        }
+    }
+
+    pub fn to_argv(&self) -> Vec<String> {
+        let mut argv = vec![self.option()];
+        if let Some(list) = self.option_list() {
+            argv.push(list);
+        }
+        argv
+    }
+}
+
+impl HelperProcess {
+    pub fn to_shell_string(&self) -> String {
+        let mut words = vec![shell_quote(&self.binary)];
+        words.extend(self.args.iter().map(|a| shell_quote(a)));
+        words.join(" ")
+    }
+}
+
+impl SetupStep {
+    //each step is skipped when what it makes is already there
+    pub fn to_shell_string(&self) -> String {
+        match self {
+            SetupStep::CreateImage { path, argv } => {
+                let words: Vec<String> = argv.iter().map(|a| shell_quote(a)).collect();
+                format!("[ -e {} ] || {}", shell_quote(path), words.join(" "))
+            },
+            SetupStep::CopyFile { from, to } => {
+                format!("[ -e {1} ] || {{ cp {0} {1} && chmod 644 {1}; }}", shell_quote(from), shell_quote(to))
+            },
+            SetupStep::CreateDir { path } => format!("mkdir -p {}", shell_quote(path)),
+        }
+    }
+
+    pub fn run(&self) -> Result<(), Error> {
+        match self {
+            SetupStep::CreateImage { path, argv } => {
+                if Path::new(path).exists() {
+                    return Ok(());
+                }
+                info!("Creating {}", path);
+                let args: Vec<&str> = argv[1..].iter().map(|a| a.as_str()).collect();
+                run_qemu_img(&argv[0], &args)?;
+            },
+            SetupStep::CopyFile { from, to } => {
+                if Path::new(to).exists() {
+                    return Ok(());
+                }
+                info!("Copying {} to {}", from, to);
+                if let Err(e) = fs::copy(from, to) {
+                    return Err(Error::new(ErrorKind::Misc, format!("Could not copy {} to {}", from, to)).with_source(e));
+                }
+                //templates are usually read only, the copy has to be writable
+                if let Err(e) = fs::set_permissions(to, fs::Permissions::from_mode(0o644)) {
+                    warn!("Could not make {} writable: {}", to, e);
+                }
+            },
+            SetupStep::CreateDir { path } => {
+                if let Err(e) = fs::create_dir_all(path) {
+                    return Err(Error::new(ErrorKind::Misc, format!("Could not create {}", path)).with_source(e));
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+impl QemuCommand {
+    pub fn new(binary: &str) -> QemuCommand {
+        QemuCommand {
+            binary: binary.to_string(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            ports: BTreeMap::new(),
+            helpers: Vec::new(),
+            setup: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, arg: QemuArg) {
+        self.args.push(arg);
+    }
+
+    pub fn extend(&mut self, args: Vec<QemuArg>) {
+        self.args.extend(args);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&QemuArg> {
+        self.args.iter().find(|a| a.name == name)
+    }
+
+    //arguments only, without the binary
+    pub fn args(&self) -> Vec<String> {
+        self.args.iter().flat_map(|a| a.to_argv()).collect()
+    }
+
+    pub fn to_argv(&self) -> Vec<String> {
+        let mut argv = vec![self.binary.clone()];
+        argv.extend(self.args());
+        argv
+    }
+
+    pub fn to_shell_string(&self) -> String {
+        self.to_argv().iter()
+            .map(|a| shell_quote(a))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commas_round_trip() {
+        let arg = QemuArg::flag("drive").prop("file", "/vms/a,b.qcow2").prop("if", "none");
+        let list = arg.option_list().unwrap();
+        assert_eq!(list, "file=/vms/a,,b.qcow2,if=none");
+        assert_eq!(QemuArg::parse("drive", &list), arg);
+        assert_eq!(QemuArg::parse("drive", &list).get_prop("file"), Some("/vms/a,b.qcow2"));
+    }
+
+    #[test]
+    fn escaped_commas_split() {
+        assert_eq!(split_option_list("a,,b,c"), vec!["a,b", "c"]);
+        assert_eq!(split_option_list("a,,,,b"), vec!["a,,b"]);
+        assert_eq!(split_option_list("a,,,b"), vec!["a,", "b"]);
+        assert_eq!(split_option_list(""), vec![""]);
+    }
+
+    #[test]
+    fn spaces_and_quotes_in_paths() {
+        let arg = QemuArg::flag("drive").prop("file", "/home/me/My VMs/it's.qcow2");
+        assert_eq!(arg.to_argv(), vec!["-drive", "file=/home/me/My VMs/it's.qcow2"]);
+        let mut cmd = QemuCommand::new("/usr/bin/qemu-system-x86_64");
+        cmd.push(arg);
+        assert_eq!(cmd.to_shell_string(), "/usr/bin/qemu-system-x86_64 -drive 'file=/home/me/My VMs/it'\\''s.qcow2'");
+    }
+
+    #[test]
+    fn shell_quoting() {
+        assert_eq!(shell_quote("if=none,id=drive0"), "if=none,id=drive0");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+    }
+
+    #[test]
+    fn first_bare_part_is_the_value() {
+        let cpu = QemuArg::parse("cpu", "host,kvm=on,+vmx");
+        assert_eq!(cpu.value.as_deref(), Some("host"));
+        assert_eq!(cpu.get_prop("kvm"), Some("on"));
+        assert_eq!(cpu.props[1], (String::from("+vmx"), None));
+    }
+
+    #[test]
+    fn bare_parts_after_props_stay_props() {
+        let spice = QemuArg::parse("spice", "port=5901,disable-ticketing");
+        assert_eq!(spice.value, None);
+        assert_eq!(spice.props[1], (String::from("disable-ticketing"), None));
+        assert_eq!(spice.get_prop("disable-ticketing"), None);
+
+        //appending to an option that has a value already
+        let mut netdev = QemuArg::with_value("netdev", "user").prop("id", "net0");
+        netdev.append_props("restrict,hostfwd=tcp::2222-:22");
+        assert_eq!(netdev.value.as_deref(), Some("user"));
+        assert_eq!(netdev.props[1], (String::from("restrict"), None));
+        assert_eq!(netdev.get_prop("hostfwd"), Some("tcp::2222-:22"));
+    }
+
+    #[test]
+    fn empty_parts_are_skipped() {
+        let arg = QemuArg::parse("device", "virtio-net-pci,netdev=net0,");
+        assert_eq!(arg.value.as_deref(), Some("virtio-net-pci"));
+        assert_eq!(arg.props, vec![(String::from("netdev"), Some(String::from("net0")))]);
+        assert_eq!(arg.option_list().unwrap(), "virtio-net-pci,netdev=net0");
+    }
+}
@@ -25,26 +25,134 @@
 
 use glob::glob;
 use serde::{Deserialize,Serialize};
-use std::path::Path;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
 use crate::{qemuconfig, utils};
-use crate::utils::find_open_socket;
+use crate::utils::{find_binary, find_open_socket, find_open_socket_excluding, port_owner};
+use crate::qemuargs::{HelperProcess, QemuArg, QemuCommand, SetupStep};
+use crate::runtime::RuntimeDir;
+use crate::usb;
+use crate::firmware;
+use crate::diskformat;
 use directories::BaseDirs;
-use std::process::Command;
-use std::collections::HashMap;
-
-#[derive(Debug)]
-pub enum ERRORCODES {
-    OpenConfigFile,
-    ReadConfigFile,
-    NoSuchFile,
-    ScsiControllerMissing,
-    UnknownDiskController,
-    MissingXdgRuntime,
-    MissingXdgConfig,
-    NoOpenPorts,
-    YAML,
-    MISC,
+use std::collections::{BTreeMap, HashMap};
+use crate::error::{Error, ErrorKind};
+use crate::configkeys::{self, UnknownKey};
+use crate::configenum::{Audio, AudioOutput, Boot, DiskInterface, DisplayDevice, Output};
+
+#[derive(Deserialize, Debug, Serialize, Clone)]
+pub struct PortForward {
+    #[serde(default = "default_forward_proto")]
+    pub proto: String, //tcp or udp
+    pub host: u16,
+    pub guest: u16,
+}
+
+#[derive(Deserialize, Debug, Serialize, Clone)]
+pub struct SerialPort {
+    pub backend: String, //pty file unix tcp telnet stdio null
+    #[serde(default)]
+    pub path: String, //file and unix, unix defaults to the runtime dir
+    #[serde(default)]
+    pub port: u16, //tcp and telnet, picked for you when 0
+}
+
+#[derive(Deserialize, Debug, Serialize, Clone)]
+pub struct Share {
+    pub path: String,
+    #[serde(default)]
+    pub tag: String, //mount tag in the guest, defaults to shareN
+    #[serde(default)]
+    pub readonly: bool,
+    #[serde(default = "default_share_driver")]
+    pub driver: String, //virtiofs or 9p
+    #[serde(default = "default_security_model")]
+    pub security_model: String, //9p only
+}
+
+#[derive(Deserialize, Debug, Serialize, Clone)]
+pub struct Disk {
+    pub path: String,
+    #[serde(default = "default_disk_size")]
+    pub size: String, //used when the image has to be created
+    #[serde(default)]
+    pub format: String, //detected from the image, or its extension when it is created
+    #[serde(default)]
+    pub interface: Option<DiskInterface>, //defaults to disk_interface
+    #[serde(default = "default_disk_cache")]
+    pub cache: String,
+    #[serde(default)]
+    pub readonly: bool,
+    #[serde(default)]
+    pub base_image: String, //created as a qcow2 overlay on top of this
+    #[serde(default)]
+    pub aio: String, //threads native io_uring, picked from cache when not set
+    #[serde(default)]
+    pub discard: String, //unmap passes the guest's trims on to the image, ignore drops them
+    #[serde(default)]
+    pub detect_zeroes: String, //off on unmap
+    //throttling, 0 is unlimited
+    #[serde(default)]
+    pub iops: u64,
+    #[serde(default)]
+    pub iops_rd: u64,
+    #[serde(default)]
+    pub iops_wr: u64,
+    #[serde(default)]
+    pub bps: u64,
+    #[serde(default)]
+    pub bps_rd: u64,
+    #[serde(default)]
+    pub bps_wr: u64,
+    #[serde(default)]
+    pub serial: String, //what the guest sees as the disk's serial number
+}
+
+impl Disk {
+    fn new(path: String, size: String) -> Disk {
+        Disk {
+            path,
+            size,
+            format: String::new(),
+            interface: None,
+            cache: default_disk_cache(),
+            readonly: false,
+            base_image: String::new(),
+            aio: String::new(),
+            discard: String::new(),
+            detect_zeroes: String::new(),
+            iops: 0,
+            iops_rd: 0,
+            iops_wr: 0,
+            bps: 0,
+            bps_rd: 0,
+            bps_wr: 0,
+            serial: String::new(),
+        }
+    }
+}
+
+fn default_disk_size() -> String {
+    String::from("128G")
+}
+
+fn default_disk_cache() -> String {
+    String::from("directsync")
+}
+
+fn default_share_driver() -> String {
+    String::from("virtiofs")
+}
+
+fn default_security_model() -> String {
+    String::from("mapped-xattr")
+}
+
+fn default_forward_proto() -> String {
+    String::from("tcp")
 }
 
 const DEFAULT_QEMU: &str  = "/snap/bin/qemu-virgil";
@@ -69,12 +177,15 @@ pub struct QuickEmuConfigOptions {
 
     boot_menu: Option<bool>,
     //display menu or not
-    boot: Option<String>, // Legacy or EFI
+    boot: Option<Boot>, //auto legacy efi
+    secure_boot: Option<bool>, //efi with the secure boot firmware
+    tpm: Option<bool>, //tpm 2.0 emulated by swtpm
 
     iso: Option<String>,
     // PATH
     driver_iso: Option<String>, //PATH
 
+    //old single disk keys, turned into [[disks]] entries
     disk_img: Option<String>,
     disk2_img: Option<String>,
     //path
@@ -84,19 +195,28 @@ pub struct QuickEmuConfigOptions {
     floppy: Option<String>, //Path
 
     //interfaces
-    disk_interface: Option<String>,
+    disk_interface: Option<DiskInterface>,
     scsi_controller: Option<String>,
 
-    display_device: Option<String>,
+    display_device: Option<DisplayDevice>,
 
-    audio: Option<String>,
-    audio_output: Option<String>,
+    audio: Option<Audio>,
+    audio_output: Option<AudioOutput>,
     //pc_spkr: Option<String>,
 
+    network: Option<String>, //user tap bridge socket none
+    nic: Option<String>, //model, defaults from guest_os
+    network_extras: Option<String>,
+    shared_folder: Option<String>, //host dir exported over smb
+    usb_devices: Option<Vec<String>>, //vendor:product ids passed through from the host
+    usb_controller: Option<String>, //none uhci ehci xhci, defaults from machine and guest_os
+    tablet: Option<bool>, //usb-tablet for absolute mouse positioning
+    keyboard_layout: Option<String>, //e.g. en-us, de
+
     //options
     virgl: Option<bool>,
     gl: Option<bool>,
-    output: Option<String>,
+    output: Option<Output>,
     output_extras: Option<String>,
     rtc: Option<bool>,
     spice: Option<bool>,
@@ -104,6 +224,11 @@ pub struct QuickEmuConfigOptions {
     qemu_path: Option<String>,
     qemu_img_path: Option<String>,
 
+    //lists of tables have to stay last or toml can't serialize the struct
+    port_forwards: Option<Vec<PortForward>>,
+    serial: Option<Vec<SerialPort>>,
+    shares: Option<Vec<Share>>,
+    disks: Option<Vec<Disk>>,
 }
 
 /*
@@ -130,34 +255,40 @@ pub struct QuickEmuConfig {
 
     pub boot_menu: bool,
     //display menu or not
-    pub boot: String, // Legacy or EFI
+    pub boot: Boot,
+    pub secure_boot: bool,
+    pub tpm: bool,
 
     pub iso: String,
     // PATH
     pub driver_iso: String, //PATH
 
-    pub disk_img: String,
-    pub disk2_img: String,
-
-    //path
-    pub disk: String, //size
-    pub disk2: String, //size
+    pub disks: Vec<Disk>,
 
     pub floppy: String, //Path
 
-    pub disk_interface: String,
+    pub disk_interface: DiskInterface,
     pub scsi_controller: String,
 
-    pub display_device: String,
+    pub display_device: DisplayDevice,
 
-    pub audio: String,
-    pub audio_output: String,
+    pub audio: Audio,
+    pub audio_output: AudioOutput,
     pub pc_spkr: String,
 
+    pub network: String,
+    pub nic: String,
+    pub network_extras: String,
+    pub shared_folder: String,
+    pub usb_devices: Vec<String>,
+    pub usb_controller: String,
+    pub tablet: Option<bool>, //None picks from the usb controller and guest_os
+    pub keyboard_layout: String,
+
     //options
     pub virgl: bool,
     pub gl: bool,
-    pub output: String,
+    pub output: Output,
     pub output_extras: String,
     pub rtc: bool,
     pub spice: bool,
@@ -165,6 +296,92 @@ pub struct QuickEmuConfig {
     pub qemu_path: String,
     pub qemu_img_path: String,
 
+    pub port_forwards: Vec<PortForward>,
+    pub serial: Vec<SerialPort>,
+    pub shares: Vec<Share>,
+
+    //keys in the config files that nothing reads
+    #[serde(skip)]
+    pub unknown_keys: Vec<UnknownKey>,
+    //the directory of the vm file
+    #[serde(skip)]
+    pub config_dir: PathBuf,
+}
+
+//a value of the wrong type is a file problem, an unknown choice or missing field a config one
+fn config_error_kind(e: &config::ConfigError) -> ErrorKind {
+    match e {
+        config::ConfigError::Message(_) => ErrorKind::InvalidConfig,
+        _ => ErrorKind::ReadConfigFile,
+    }
+}
+
+//the merged options with the defaults filled in
+pub fn from_options(cfg: QuickEmuConfigOptions, config: &str, unknown_keys: Vec<UnknownKey>) -> QuickEmuConfig {
+    let filename = Path::new(config).file_stem().and_then(OsStr::to_str).unwrap_or("vm");
+    let mut disks = Vec::new();
+    for (img, size) in [(cfg.disk_img, cfg.disk), (cfg.disk2_img, cfg.disk2)] {
+        if let Some(path) = img.filter(|p| !p.is_empty()) {
+            warn!("disk_img and disk2_img are deprecated, use a [[disks]] entry for {}", path);
+            disks.push(Disk::new(path, size.unwrap_or_else(default_disk_size)));
+        }
+    }
+    disks.extend(cfg.disks.unwrap_or_default());
+    let machine_given = cfg.machine.is_some();
+    let mut q = QuickEmuConfig {
+        vmname: cfg.vmname.unwrap_or(String::from(filename)),
+        launcher: cfg.launcher.unwrap_or("slquickemu".to_string()),
+        guest_os: cfg.guest_os.unwrap_or("linux".to_string()),
+        kvm: cfg.kvm.unwrap_or(true),
+        cpu: cfg.cpu.unwrap_or("-cpu host,kvm=on".to_string()),
+        ram: cfg.ram.unwrap_or("auto".to_string()),
+        cpu_cores: cfg.cpu_cores.unwrap_or(0u8),
+        machine: cfg.machine.unwrap_or("q35".to_string()),
+        boot_menu: cfg.boot_menu.unwrap_or(false),
+        boot: cfg.boot.unwrap_or(Boot::Auto),
+        secure_boot: cfg.secure_boot.unwrap_or(false),
+        tpm: cfg.tpm.unwrap_or(false),
+        iso: cfg.iso.unwrap_or("".to_string()),
+        driver_iso: cfg.driver_iso.unwrap_or("".to_string()),
+        disks,
+        floppy: cfg.floppy.unwrap_or("".to_string()),
+        disk_interface: cfg.disk_interface.unwrap_or(DiskInterface::Virtio),
+        scsi_controller: cfg.scsi_controller.unwrap_or("lsi".to_string()),
+        display_device: cfg.display_device.unwrap_or(DisplayDevice::Vga),
+        audio: cfg.audio.unwrap_or(Audio::IntelHda),
+        audio_output: cfg.audio_output.unwrap_or(AudioOutput::Pa),
+        pc_spkr: "".to_string(),
+        network: cfg.network.unwrap_or("user".to_string()),
+        nic: cfg.nic.unwrap_or("".to_string()),
+        network_extras: cfg.network_extras.unwrap_or("".to_string()),
+        shared_folder: cfg.shared_folder.unwrap_or("".to_string()),
+        usb_devices: cfg.usb_devices.unwrap_or_default(),
+        usb_controller: cfg.usb_controller.unwrap_or("".to_string()),
+        tablet: cfg.tablet,
+        keyboard_layout: cfg.keyboard_layout.unwrap_or("".to_string()),
+        virgl: cfg.virgl.unwrap_or(true),
+        gl: cfg.gl.unwrap_or(true),
+        rtc: cfg.rtc.unwrap_or(true),
+        spice: cfg.spice.unwrap_or(true),
+        output: cfg.output.unwrap_or(Output::Sdl),
+        output_extras: cfg.output_extras.unwrap_or("".to_string()),
+        qemu_path: cfg.qemu_path.unwrap_or(String::from(DEFAULT_QEMU)),
+        qemu_img_path: cfg.qemu_img_path.unwrap_or(String::from(DEFAULT_QEMU_IMG)),
+        port_forwards: cfg.port_forwards.unwrap_or_default(),
+        serial: cfg.serial.unwrap_or_default(),
+        shares: cfg.shares.unwrap_or_default(),
+        unknown_keys,
+        config_dir: match Path::new(config).parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+    };
+    //isa devices pick the machine that has an isa bus, unless one was asked for
+    if !machine_given && has_isa_devices(&q) {
+        info!("{} has ISA devices, using the isapc machine", q.vmname);
+        q.machine = String::from("isapc");
+    }
+    q
 }
 
 fn get_empty_config() -> QuickEmuConfigOptions
@@ -180,6 +397,8 @@ fn get_empty_config() -> QuickEmuConfigOptions
         machine: None,
         boot_menu: None,
         boot: None,
+        secure_boot: None,
+        tpm: None,
         iso: None,
         driver_iso: None,
         disk_img: None,
@@ -192,6 +411,14 @@ fn get_empty_config() -> QuickEmuConfigOptions
         display_device: None,
         audio: None,
         audio_output: None,
+        network: None,
+        nic: None,
+        network_extras: None,
+        shared_folder: None,
+        usb_devices: None,
+        usb_controller: None,
+        tablet: None,
+        keyboard_layout: None,
         virgl: None,
         gl: None,
         output: None,
@@ -199,19 +426,61 @@ fn get_empty_config() -> QuickEmuConfigOptions
         rtc: None,
         spice: None,
         qemu_path: None,
-        qemu_img_path: None
+        qemu_img_path: None,
+        port_forwards: None,
+        serial: None,
+        shares: None,
+        disks: None,
     }
 }
 
-pub fn setup_options(config: &str) -> Result<QuickEmuConfig, ERRORCODES> {
+//the keys a config table can have, "" is the top level, the others are its lists of tables
+pub fn known_keys(table: &str) -> Vec<String> {
+    let sample = match table {
+        "" => serde_json::to_value(get_empty_config()),
+        "disks" => serde_json::to_value(Disk::new(String::new(), String::new())),
+        "serial" => serde_json::to_value(SerialPort { backend: String::new(), path: String::new(), port: 0 }),
+        "shares" => serde_json::to_value(Share {
+            path: String::new(),
+            tag: String::new(),
+            readonly: false,
+            driver: String::new(),
+            security_model: String::new(),
+        }),
+        "port_forwards" => serde_json::to_value(PortForward { proto: String::new(), host: 0, guest: 0 }),
+        _ => return Vec::new(),
+    };
+    match sample {
+        Ok(serde_json::Value::Object(map)) => map.keys().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+//each section of a tweak file is a layer of its own
+fn tweak_unknown_keys(path: &str) -> Vec<UnknownKey> {
+    let mut file = config::Config::default();
+    if file.merge(config::File::with_name(path)).is_err() {
+        return Vec::new();
+    }
+    match file.try_into::<BTreeMap<String, serde_json::Value>>() {
+        Ok(sections) => sections.iter()
+            .flat_map(|(section, table)| configkeys::find_unknown_keys(path, section, table))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn setup_options(config: &str) -> Result<QuickEmuConfig, Error> {
     let empty_config = get_empty_config();
     let mut cfgfile = config::Config::default();
     let mut tweaks_cfg = config::Config::default();
+    let mut unknown_keys = Vec::new();
+    let mut have_tweaks = false;
     debug!("Attempting to load tweaks file");
     let xdg_config_dir = get_xdg_config_dir();
     let xdg_config_str = match xdg_config_dir {
         Ok(t) => t,
-        Err(e) => {
+        Err(_e) => {
             warn!("Couldn't find XDG CONFIG DIR, TWEAK LOADING WILL FAIL");
             String::from("")
         }
@@ -227,23 +496,30 @@ pub fn setup_options(config: &str) -> Result<QuickEmuConfig, ERRORCODES> {
                 let cfgfile_tmp = t.to_str().unwrap();
                 match tweaks_cfg.merge(config::File::with_name(cfgfile_tmp))
                 {
-                    Ok(_t) => debug!("Loaded tweaks file!"),
+                    Ok(_t) => {
+                        debug!("Loaded tweaks file!");
+                        have_tweaks = true;
+                    },
                     Err(_e) => { error!("Failed to load tweaks file!") }
                 }
+                unknown_keys.extend(tweak_unknown_keys(cfgfile_tmp));
         },
             Err(e) => println!("{}",e),
         }
     }
 
-    let tweaks = tweaks_cfg.try_into::<HashMap<String,QuickEmuConfigOptions>>();
+    //with no tweak files there is nothing to convert, an empty config isn't a table
+    let tweaks = if have_tweaks {
+        tweaks_cfg.try_into::<HashMap<String,QuickEmuConfigOptions>>()
+    } else {
+        Ok(HashMap::new())
+    };
 
+    //carrying on without them would drop [defaults] and every other section too
     let tweaks = match tweaks {
         Ok(t) => t,
         Err(x) => {
-            error!("ERROR Loading your tweaks file : {}",x);
-            let a: HashMap<String,QuickEmuConfigOptions> = HashMap::new();
-            a
-            //return Err(qemuconfig::ERRORCODES::ReadConfigFile)
+            return Err(Error::new(config_error_kind(&x), format!("Tweak files in {}/slquickemu have a bad value", xdg_config_str)).with_source(x));
         }
     };
     debug!("Attempting to load config file");
@@ -252,12 +528,20 @@ pub fn setup_options(config: &str) -> Result<QuickEmuConfig, ERRORCODES> {
     match cfgfile.merge(config::File::with_name(config)){
         Ok(_t) => debug!("Loaded config file {}",config),
         Err(e) => {
-            error!("Error {}", e);
-            return Err(qemuconfig::ERRORCODES::ReadConfigFile);
+            //the config crate also finds myvm.toml when given myvm
+            let found = Path::new(config).exists() || glob(&format!("{}.*", config))
+                .map(|mut g| g.next().is_some())
+                .unwrap_or(false);
+            if !found {
+                return Err(Error::new(ErrorKind::NoSuchFile, format!("Config file {} does not exist", config)));
+            }
+            return Err(Error::new(ErrorKind::ReadConfigFile, format!("Could not load config file {}", config)).with_source(e));
         }
     }
+    if let Ok(table) = cfgfile.clone().try_into::<serde_json::Value>() {
+        unknown_keys.extend(configkeys::find_unknown_keys(config, "", &table));
+    }
     let loaded = cfgfile.try_into::<qemuconfig::QuickEmuConfigOptions>();
-    let filename = Path::new(config).file_stem().and_then(OsStr::to_str).unwrap_or("vm");
     match loaded {
         Ok(mut cfg) => {
             cfgfile = config::Config::default();
@@ -266,12 +550,14 @@ pub fn setup_options(config: &str) -> Result<QuickEmuConfig, ERRORCODES> {
             {
                 debug!("Found defaults file. Loading that.");
                 let defaults_string = toml::to_string(tweaks.get("defaults").unwrap()).unwrap();
-                cfgfile.merge(config::File::from_str(&defaults_string,config::FileFormat::Toml));
+                if let Err(e) = cfgfile.merge(config::File::from_str(&defaults_string,config::FileFormat::Toml)) {
+                    error!("Failed to apply defaults: {}", e);
+                }
             } else {
                 warn!("No default file found, using built in defaults!");
             }
             let config_string = toml::to_string(&cfg).unwrap_or("".to_string());
-            let guest_os = cfg.guest_os.unwrap_or("linux".to_string());
+            let guest_os = cfg.guest_os.clone().unwrap_or("linux".to_string());
             debug!("Found {} for guest os!",guest_os);
             debug!("Checking for tweaks!");
             if tweaks.contains_key(&guest_os) {
@@ -288,308 +574,700 @@ pub fn setup_options(config: &str) -> Result<QuickEmuConfig, ERRORCODES> {
                 debug!("Tweaks should be applied!")
             }
             debug!("On we plow...");
-            let q = QuickEmuConfig {
-                vmname: cfg.vmname.unwrap_or(String::from(filename)),
-                launcher: cfg.launcher.unwrap_or("slquickemu".to_string()),
-                guest_os: guest_os,
-                kvm: cfg.kvm.unwrap_or(true),
-                cpu: cfg.cpu.unwrap_or("-cpu host,kvm=on".to_string()),
-                ram: cfg.ram.unwrap_or("auto".to_string()),
-                cpu_cores: cfg.cpu_cores.unwrap_or(0u8),
-                machine: cfg.machine.unwrap_or("q35".to_string()),
-                boot_menu: cfg.boot_menu.unwrap_or(false),
-                boot: cfg.boot.unwrap_or("".to_string()),
-                iso: cfg.iso.unwrap_or("".to_string()),
-                driver_iso: cfg.driver_iso.unwrap_or("".to_string()),
-                disk_img: cfg.disk_img.unwrap_or("".to_string()),
-                disk: cfg.disk.unwrap_or("128G".to_string()),
-                disk2_img: cfg.disk2_img.unwrap_or("".to_string()),
-                disk2: cfg.disk2.unwrap_or("128G".to_string()),
-                floppy: cfg.floppy.unwrap_or("".to_string()),
-                disk_interface: cfg.disk_interface.unwrap_or("none".to_string()),
-                scsi_controller: cfg.scsi_controller.unwrap_or("lsi".to_string()),
-                display_device: cfg.display_device.unwrap_or("vga".to_string()),
-                audio: cfg.audio.unwrap_or("intel-hda".to_string()),
-                audio_output: cfg.audio_output.unwrap_or("pa".to_string()),
-                pc_spkr: "".to_string(),
-                virgl: cfg.virgl.unwrap_or(true),
-                gl: cfg.gl.unwrap_or(true),
-                rtc: cfg.rtc.unwrap_or(true),
-                spice: cfg.spice.unwrap_or(true),
-                output: cfg.output.unwrap_or("sdl".to_string()),
-                output_extras: cfg.output_extras.unwrap_or("".to_string()),
-                qemu_path: cfg.qemu_path.unwrap_or(String::from(DEFAULT_QEMU)),
-                qemu_img_path: cfg.qemu_img_path.unwrap_or(String::from(DEFAULT_QEMU_IMG)),
-            };
             //left space to do anything I need to correct before passing this out
-            Ok(q)
+            Ok(from_options(cfg, config, unknown_keys))
+        },
+        Err(e) => {
+            Err(Error::new(config_error_kind(&e), format!("Config file {} has a bad value", config)).with_source(e))
         },
-        Err(_e) => Err(ERRORCODES::ReadConfigFile),
     }
 }
 
 
-pub fn build_config(config: &qemuconfig::QuickEmuConfig) -> Result<Vec<String>, qemuconfig::ERRORCODES> {
+/*
+ * The qemu command line for a vm. Nothing on the host is checked or
+ * written here, so the print modes can run anywhere: the host checks are
+ * the validator's, the files the vm needs are setup steps for the launcher.
+ */
+pub fn build_config(config: &qemuconfig::QuickEmuConfig) -> Result<QemuCommand, Error> {
+    let mut cmd = QemuCommand::new(&config.qemu_path);
 
-    /*
-    let sss = toml::to_string(&config).unwrap();
-    println!("");
-    println!("{}",sss);
-    println!("");
-*/
-    let (cpu,machine, kvm ) = set_cpu_cmd(config)?;
+    let cpu = set_cpu_cmd(config)?;
     let cpu_cores = set_cpu_cores(config);
-    let ram = set_ram_value(config);
+    let ram = set_ram_value(config)?;
+    let (shares_cmd, share_helpers) = set_shares_cmd(config)?;
     let floppy = set_floppy(config)?;
     let boot_menu = set_boot_menu(config);
-    let disk_img = handle_disk_image(&config.qemu_img_path
-                                     , &config.disk_img, &config.disk);
-    let disk2_img = handle_disk_image(&config.qemu_img_path
-                                      , &config.disk2_img, &config.disk);
-    let mut vec = Vec::new();
-
-    //let floppy = set_floppy_cmd(floppy);
-
-    let drive_cmd = set_drive_cmd(config, &disk_img, 0)?;
-    let drive2_cmd = set_drive_cmd(config, &disk2_img, 1)?;
+    let efi_cmd = set_efi_cmd(config, &mut cmd.setup)?;
+    let (tpm_cmd, tpm_helper) = set_tpm_cmd(config, &mut cmd.setup)?;
+    let mut disks = Vec::new();
+    for disk in config.disks.iter() {
+        let (disk, step) = get_disk_setup(&config.qemu_img_path, disk)?;
+        disks.push(disk);
+        cmd.setup.extend(step);
+    }
+    let (drive_cmd, usb_drive_cmd, ide_index) = set_drive_cmd(config, &disks)?;
 
     let cdrom = set_iso_file(config.iso.as_str())?;
     let driver_cdrom = set_iso_file(config.driver_iso.as_str())?;
-    let cdrom_cmd = set_cdrom_cmd(config,&cdrom, 0);
-    let cdrom2_cmd = set_cdrom_cmd(config, &driver_cdrom, 1);
-
-    let disp = config.display_device.clone();
-
-    let virgl = String::from("on");
-    let video_cmd = set_video_cmd(disp, virgl);
-
-    let (gl,output,output_extras) = get_output_gl_virgl(&config)?;
-
-    let rtc = if config.rtc {
-        String::from("-rtc base=localtime,clock=host")
-    } else {
-        String::new()
-    };
-
-    let xdg = get_xdg_runtime_dir()?;
-
-    let mut audio_output = format!("-audiodev {0},id={0}",config.audio_output);
-    if config.audio_output.eq("pa")
-    {
-        audio_output += &*format!(",server=unix:{0}/pulse/native,\
-                        out.stream-name={1}-{2},\
-                        in.stream-name={1}-{2} \
-                        -device {3}", xdg, config.launcher, config.vmname, config.audio);
-    }
-
-    if config.audio.contains("hda") || config.audio.contains("intel") {
-        audio_output += " -device hda-duplex,mixer=off";
+    let cdrom_cmd = set_cdrom_cmd(config, &cdrom, ide_index)?;
+    let cdrom2_cmd = set_cdrom_cmd(config, &driver_cdrom, ide_index + 1)?;
+
+    let video_cmd = set_video_cmd(config.display_device, "on");
+    let display_cmd = set_display_cmd(config)?;
+
+    let audio_cmd = set_audio_cmd(config)?;
+    let usb_cmd = set_usb_cmd(config)?;
+    let network_cmd = set_network_cmd(config)?;
+    //host ports are unique per protocol, several may go to the same guest port
+    for fwd in config.port_forwards.iter() {
+        cmd.ports.insert(format!("hostfwd-{}-{}", fwd.proto, fwd.host), fwd.host);
     }
-        audio_output += &*format!(",audiodev={}",config.audio_output);
 
     let open_port = find_open_socket(5900)?;
-    let spice_port = if config.spice && open_port > 0
+    let spice_cmd = if config.spice && open_port > 0
     {
-        format!("-spice port={},disable-ticketing",open_port)
+        vec![QemuArg::flag("spice")
+            .prop("port", &open_port.to_string())
+            .bare_prop("disable-ticketing")]
     } else {
-        String::from("")
+        Vec::new()
     };
 
     //TODO
     //rng
-    //serial port
     //extra options
-    if disk_img.eq("") && disk2_img.eq("") && cdrom.eq("") {
+    if config.disks.is_empty() && cdrom.is_empty() {
         info!("no disk images have been set, is this a mistake?");
     }
 
+    cmd.push(QemuArg::with_value("name", &config.vmname).prop("process", &config.vmname));
+    cmd.push(set_qmp_cmd(config)?);
+    cmd.extend(cpu);
+    cmd.push(QemuArg::with_value("smp", &cpu_cores.to_string())
+        .prop("sockets", "1")
+        .prop("cores", &cpu_cores.to_string())
+        .prop("threads", "1"));
+    cmd.extend(ram);
+    cmd.push(boot_menu);
+    cmd.extend(efi_cmd);
+    cmd.extend(tpm_cmd);
+    cmd.helpers.extend(tpm_helper);
+    cmd.push(video_cmd);
+    cmd.push(display_cmd);
+    cmd.extend(floppy);
+    cmd.extend(drive_cmd);
+    cmd.extend(cdrom_cmd);
+    cmd.extend(cdrom2_cmd);
+    if config.rtc {
+        cmd.push(QemuArg::flag("rtc").prop("base", "localtime").prop("clock", "host"));
+    }
+    cmd.extend(audio_cmd);
+    cmd.extend(usb_cmd);
+    cmd.extend(usb_drive_cmd);
+    cmd.extend(network_cmd);
+    let serial_cmd = set_serial_cmd(config, &mut cmd.ports)?;
+    cmd.extend(serial_cmd);
+    cmd.extend(shares_cmd);
+    cmd.helpers.extend(share_helpers);
+    cmd.extend(spice_cmd);
+    if config.spice {
+        cmd.ports.insert(String::from("spice"), open_port);
+    }
 
-    vec.push(format!("-name {0},process={0}",config.vmname));
-    vec.push(format!("{} {} -machine {}",kvm,cpu,machine));
-    vec.push(format!("-smp {0},sockets=1,cores={0},threads=1",cpu_cores));
-    vec.push(format!("-m {}",ram));
-    vec.push(format!("{}",boot_menu));
-    vec.push(format!("{} -display {},gl={}{}",video_cmd, output,gl,output_extras));
-    vec.push(video_cmd);
-    vec.push(floppy);
-    vec.push(drive_cmd);
-    vec.push(drive2_cmd);
-    vec.push(cdrom_cmd);
-    vec.push(cdrom2_cmd);
-    vec.push(rtc);
-    vec.push(audio_output);
-    vec.push(spice_port);
-
-    Ok(vec)
+    Ok(cmd)
 
 }
 
-fn get_xdg_config_dir() -> Result<String, qemuconfig::ERRORCODES>
+fn get_xdg_config_dir() -> Result<String, Error>
 {
     let xdg_dir = BaseDirs::new();
     let l = match xdg_dir {
         Some(t) => t,
-        None => return Err(ERRORCODES::MissingXdgConfig),
+        None => return Err(Error::new(ErrorKind::MissingXdgConfig, "Can't find the home directory")),
     }   ;
     let xdg_config_dir = l.config_dir().to_str();
     match xdg_config_dir
     {
         Some(t) => Ok(String::from(t)),
-        None => return Err(ERRORCODES::MissingXdgConfig),
+        None => Err(Error::new(ErrorKind::MissingXdgConfig, "The XDG config dir is not valid unicode")),
     }
 }
 
-fn get_xdg_runtime_dir() -> Result<String, qemuconfig::ERRORCODES>{
+pub fn get_xdg_runtime_dir() -> Result<String, Error>{
     let xdg_dir = BaseDirs::new();
     let l = match xdg_dir {
         Some(x) => {
             x
         },
-        None => return Err(qemuconfig::ERRORCODES::MissingXdgConfig),
+        None => return Err(Error::new(ErrorKind::MissingXdgConfig, "Can't find the home directory")),
     };
 
     let xdg_runtime_dir = match l.runtime_dir()
     {
         Some(x) => x.to_str(),
-        None => return Err(qemuconfig::ERRORCODES::MissingXdgRuntime),
+        None => return Err(Error::new(ErrorKind::MissingXdgRuntime, "XDG_RUNTIME_DIR is not set")),
     };
 
     let acutual_xdg_runtime_dir = match xdg_runtime_dir
     {
         Some(x) => x,
-        None => return Err(qemuconfig::ERRORCODES::MissingXdgRuntime),
+        None => return Err(Error::new(ErrorKind::MissingXdgRuntime, "XDG_RUNTIME_DIR is not valid unicode")),
     };
 
     Ok(acutual_xdg_runtime_dir.to_string())
 }
 
-fn set_cpu_cmd(config: &qemuconfig::QuickEmuConfig) -> Result<(String, String, String), qemuconfig::ERRORCODES>
-{
-    let cpu: String = if !config.cpu.starts_with("-cpu")
-    {
-        format!("-cpu {}",config.cpu)
-    } else {
-        config.cpu.clone()
-    };
-    let mut machine = config.machine.clone();
+fn has_isa_devices(config: &qemuconfig::QuickEmuConfig) -> bool {
+    config.display_device.is_isa() || config.disks.iter().any(|d| get_disk_interface(config, d) == DiskInterface::IsaIde)
+        || config.disk_interface == DiskInterface::IsaIde || config.nic.contains("isa")
+}
 
-    //final things
-    if config.display_device.contains("isa") || config.disk_interface.contains("isa")
-    {
-        machine = String::from("isapc");
+pub fn get_machine(config: &qemuconfig::QuickEmuConfig) -> String {
+    config.machine.clone()
+}
+
+//isa devices only work on the isapc machine
+pub fn check_machine(config: &qemuconfig::QuickEmuConfig) -> Result<(), Error> {
+    if has_isa_devices(config) && config.machine.ne("isapc") {
+        return Err(Error::new(ErrorKind::MachineMismatch, format!("ISA DEVICES NEED THE isapc MACHINE, NOT {}, SET machine = \"isapc\" OR LEAVE IT OUT", config.machine)));
     }
+    Ok(())
+}
 
-    let kvm =if !config.kvm {
-        String::from("")
-    } else {
-        String::from("-enable-kvm")
-    };
-    Ok((cpu,machine,kvm))
+fn set_cpu_cmd(config: &qemuconfig::QuickEmuConfig) -> Result<Vec<QemuArg>, Error>
+{
+    let mut args = Vec::new();
+    if config.kvm {
+        args.push(QemuArg::flag("enable-kvm"));
+    }
+    let cpu = config.cpu.trim_start_matches("-cpu").trim();
+    args.push(QemuArg::parse("cpu", cpu));
+    check_machine(config)?;
+    let mut machine = QemuArg::with_value("machine", &get_machine(config));
+    //secure boot firmware keeps its variables where only smm code can write them
+    if config.secure_boot {
+        machine = machine.prop("smm", "on");
+    }
+    args.push(machine);
+    Ok(args)
 }
 
-fn get_output_gl_virgl(config: &qemuconfig::QuickEmuConfig) -> Result<(String, String, String), qemuconfig::ERRORCODES>
+//every vm gets a qmp socket in its runtime dir so it can be managed later
+fn set_qmp_cmd(config: &qemuconfig::QuickEmuConfig) -> Result<QemuArg, Error>
 {
-    let mut gl;
-    let mut output_extras = String::new();
+    let rt = RuntimeDir::for_vm(&config.vmname)?;
+    Ok(QemuArg::with_value("qmp", &format!("unix:{}", rt.qmp_socket().display()))
+        .prop("server", "on")
+        .prop("wait", "off"))
+}
 
-    if config.gl
-    {
-        gl = String::from("on")
-    } else {
-        gl = String::from("off")
+//user facing nic name, the qemu device, whether it needs a pci bus
+pub const NIC_MODELS: [(&str, &str, bool); 6] = [
+    ("virtio-net", "virtio-net-pci", true),
+    ("e1000", "e1000", true),
+    ("rtl8139", "rtl8139", true),
+    ("ne2k_pci", "ne2k_pci", true),
+    ("ne2k_isa", "ne2k_isa", false),
+    ("pcnet", "pcnet", true),
+];
+
+//pick a card the guest has drivers for out of the box
+pub fn get_nic_model(config: &qemuconfig::QuickEmuConfig) -> String {
+    if !config.nic.is_empty() {
+        return config.nic.clone();
+    }
+    if get_machine(config).eq("isapc") {
+        return String::from("ne2k_isa");
+    }
+    match config.guest_os.as_str() {
+        "windows" => String::from("e1000"),
+        "dos" | "win9x" | "windows98" | "win98" | "win95" => String::from("pcnet"),
+        "macos" => String::from("e1000"),
+        _ => String::from("virtio-net"),
     }
+}
 
-    if config.output.eq("gtk")
-    {
-        if gl.eq("on") {
-            gl = String::from("es");
+//port forwards and the smb share are slirp features, the other backends don't have them
+pub fn check_user_network(config: &qemuconfig::QuickEmuConfig) -> Result<(), Error> {
+    if config.network.eq("user") {
+        return Ok(());
+    }
+    if !config.port_forwards.is_empty() {
+        return Err(Error::new(ErrorKind::PortForward, format!("PORT FORWARDS ONLY WORK WITH USER NETWORKING, NOT {}", config.network)));
+    }
+    if !config.shared_folder.is_empty() {
+        return Err(Error::new(ErrorKind::MissingSmbd, format!("SHARED FOLDERS ONLY WORK WITH USER NETWORKING, NOT {}", config.network)));
+    }
+    Ok(())
+}
+
+//seen holds the forwards checked before this one
+pub fn check_port_forward(fwd: &PortForward, seen: &mut Vec<(String, u16)>) -> Result<(), Error> {
+    if fwd.proto.ne("tcp") && fwd.proto.ne("udp") {
+        return Err(Error::new(ErrorKind::PortForward, format!("PORT FORWARD PROTOCOL {} IS UNKNOWN, USE tcp OR udp", fwd.proto)));
+    }
+    if seen.contains(&(fwd.proto.clone(), fwd.host)) {
+        return Err(Error::new(ErrorKind::PortForward, format!("{} host port {} is forwarded twice", fwd.proto, fwd.host)));
+    }
+    seen.push((fwd.proto.clone(), fwd.host));
+    Ok(())
+}
+
+//the host port of a forward has to be free when the vm starts
+pub fn check_host_port(config: &qemuconfig::QuickEmuConfig, fwd: &PortForward) -> Result<(), Error> {
+    debug!("Checking {} host port {}", fwd.proto, fwd.host);
+    if let Some(owner) = port_owner(&fwd.proto, fwd.host)? {
+        return Err(Error::new(ErrorKind::PortInUse, format!("Can't forward {} port {} to {}: it is already used by {}", fwd.proto, fwd.host, config.vmname, owner)));
+    }
+    Ok(())
+}
+
+//hostfwd rules for user networking
+fn set_port_forwards(config: &qemuconfig::QuickEmuConfig) -> Result<Vec<String>, Error>
+{
+    let mut rules = Vec::new();
+    let mut seen = Vec::new();
+    for fwd in config.port_forwards.iter() {
+        check_port_forward(fwd, &mut seen)?;
+        rules.push(format!("{}::{}-:{}", fwd.proto, fwd.host, fwd.guest));
+    }
+    Ok(rules)
+}
+
+//where the guest finds the share, qemu's user net always puts smb on 10.0.2.4
+pub const SMB_UNC_PATH: &str = "\\\\10.0.2.4\\qemu";
+
+//qemu runs its own smbd for the share, so one has to be installed
+pub fn check_smb_share(config: &qemuconfig::QuickEmuConfig) -> Result<(), Error>
+{
+    if config.shared_folder.is_empty() {
+        return Ok(());
+    }
+    if !Path::new(&config.shared_folder).is_dir() {
+        return Err(Error::new(ErrorKind::NoSuchFile, format!("Shared folder {} is not a directory!", config.shared_folder)));
+    }
+    match find_binary("smbd") {
+        Some(smbd) => debug!("Using {} for the shared folder", smbd.display()),
+        None => {
+            return Err(Error::new(ErrorKind::MissingSmbd, "smbd was not found, install samba to use shared_folder"));
         }
-        output_extras = String::from(",grab-on-hover=on,zoom-to-fit=on");
     }
+    Ok(())
+}
 
-    if config.output.eq("curses")
-    {
-        gl = String::from("off");
+//controller name and the qemu device for it
+pub const USB_CONTROLLERS: [(&str, &str); 3] = [
+    ("uhci", "piix3-usb-uhci"),
+    ("ehci", "usb-ehci"),
+    ("xhci", "qemu-xhci"),
+];
+
+fn is_legacy_os(guest_os: &str) -> bool {
+    matches!(guest_os, "dos" | "win9x" | "windows98" | "win98" | "win95")
+}
+
+//isapc has no pci bus, dos has no usb stack and win9x only knows uhci
+pub fn get_usb_controller(config: &qemuconfig::QuickEmuConfig) -> String {
+    if !config.usb_controller.is_empty() {
+        return config.usb_controller.clone();
+    }
+    if get_machine(config).eq("isapc") || config.guest_os.eq("dos") {
+        return String::from("none");
+    }
+    if is_legacy_os(&config.guest_os) {
+        return String::from("uhci");
     }
+    String::from("xhci")
+}
 
-    output_extras = set_output_extras(config, &output_extras);
-    let output = config.output.clone();
-    Ok((gl,output,output_extras))
+fn get_tablet(config: &qemuconfig::QuickEmuConfig) -> bool {
+    match config.tablet {
+        Some(t) => t,
+        None => get_usb_controller(config).ne("none") && !is_legacy_os(&config.guest_os),
+    }
 }
 
-fn set_output_extras(config: &qemuconfig::QuickEmuConfig, output_extras: &String) -> String{
-    if config.output_extras.ne("")
-    {
-        let mut temp_oe;
-        temp_oe = String::from("");
-        debug!("Tempoe is {}",temp_oe);
-        if config.output_extras.starts_with(',') {
-            temp_oe = format!("{}", config.output_extras);
-        } else {
-            temp_oe = format!(",{}", config.output_extras);
+//qemu's device for the usb controller, None when the vm has none
+pub fn get_usb_controller_device(config: &qemuconfig::QuickEmuConfig) -> Result<Option<&'static str>, Error> {
+    let controller = get_usb_controller(config);
+    if controller.eq("none") {
+        if config.tablet == Some(true) || !config.usb_devices.is_empty() {
+            return Err(Error::new(ErrorKind::UnknownUsbController, "USB_CONTROLLER IS none BUT tablet OR usb_devices NEED ONE"));
         }
-        format!("{}{}", output_extras,temp_oe)
-    } else {
-        format!("{}", output_extras)
+        return Ok(None);
+    }
+    let device = match USB_CONTROLLERS.iter().find(|(name, _)| name.eq(&controller)) {
+        Some((_, device)) => *device,
+        None => {
+            return Err(Error::new(ErrorKind::UnknownUsbController, format!("USB CONTROLLER {} IS UNKNOWN, USE none, uhci, ehci OR xhci", controller)));
+        }
+    };
+    if get_machine(config).eq("isapc") {
+        return Err(Error::new(ErrorKind::MachineMismatch, format!("THE isapc MACHINE HAS NO PCI BUS FOR A {} USB CONTROLLER", controller)));
     }
+    Ok(Some(device))
 }
 
+pub fn get_usb_id(id: &str) -> Result<(String, String), Error> {
+    match usb::parse_usb_id(id) {
+        Some(v) => Ok(v),
+        None => Err(Error::new(ErrorKind::BadUsbId, format!("USB DEVICE {} IS NOT A vendor:product ID LIKE 046d:c52b", id))),
+    }
+}
 
-fn set_video_cmd(disp: String, virgl: String) -> String {
-   if disp.contains("cirrus") {
-        if disp.contains("isa") {
-            format!("-device isa-cirrus-vga")
-        } else {
-            format!("-device cirrus-vga")
+//whether a passed through device is plugged in, one that is has to be ours to open
+pub fn check_usb_device(root: &Path, id: &str) -> Result<bool, Error> {
+    let (vendor, product) = get_usb_id(id)?;
+    let found = usb::find_devices(root, &vendor, &product);
+    for dev in found.iter() {
+        if !dev.accessible() {
+            return Err(Error::new(ErrorKind::UsbPermission, format!("Can't open {} for USB device {} ({} {}), add a udev rule or give your user access to it", dev.dev_node().display(), id, dev.manufacturer, dev.name)));
         }
-    } else if disp.contains("bochs") {
-        format!("-device bochs-display")
-    } else if disp.contains("ati") {
-        format!("-device ati-vga")
-    } else if disp.contains("vmware") {
-        format!("-device vmware-svga")
-    } else if disp.contains("qxl") {
-        format!("-device qxl-vga")
-    } else if disp.contains("virtio") {
-        format!("-device virtio-vga,virgl={}", virgl)
-    } else if disp.contains("vga") {
-        if disp.contains("isa") {
-            format!("-device isa-vga")
-        } else {
-            format!("-device VGA,vgamem_mb=128")
+    }
+    Ok(!found.is_empty())
+}
+
+pub fn usb_unplugged_warning(id: &str) -> String {
+    format!("USB device {} is not plugged in, it will be attached when it is", id)
+}
+
+/*
+ * The usb controller with the tablet, keyboard and passed through host
+ * devices on it. Host devices that are not plugged in are left to qemu,
+ * which attaches them when they show up, but one we can see and can't open
+ * would only fail later inside qemu.
+ */
+fn set_usb_cmd(config: &qemuconfig::QuickEmuConfig) -> Result<Vec<QemuArg>, Error>
+{
+    let mut args = Vec::new();
+    if !config.keyboard_layout.is_empty() {
+        args.push(QemuArg::with_value("k", &config.keyboard_layout));
+    }
+    let tablet = get_tablet(config);
+    let device = match get_usb_controller_device(config)? {
+        Some(d) => d,
+        None => return Ok(args),
+    };
+    args.push(QemuArg::with_value("device", device).prop("id", "usb"));
+    if tablet {
+        args.push(QemuArg::with_value("device", "usb-tablet").prop("bus", "usb.0"));
+    }
+    //macos has no ps/2 driver
+    if config.guest_os.eq("macos") {
+        args.push(QemuArg::with_value("device", "usb-kbd").prop("bus", "usb.0"));
+    }
+
+    for id in config.usb_devices.iter() {
+        let (vendor, product) = get_usb_id(id)?;
+        args.push(QemuArg::with_value("device", "usb-host")
+            .prop("bus", "usb.0")
+            .prop("vendorid", &format!("0x{}", vendor))
+            .prop("productid", &format!("0x{}", product)));
+    }
+    Ok(args)
+}
+
+//qemu's device for the nic model, which has to fit the machine
+pub fn get_nic_device(config: &qemuconfig::QuickEmuConfig) -> Result<&'static str, Error> {
+    let model = get_nic_model(config);
+    let (device, needs_pci) = match NIC_MODELS.iter().find(|(name, dev, _)| model.eq(name) || model.eq(dev)) {
+        Some((_, dev, pci)) => (*dev, *pci),
+        None => {
+            return Err(Error::new(ErrorKind::UnknownNetworkDevice, format!("NIC MODEL {} IS UNKNOWN", model)));
         }
-    } else {
-        format!("-device VGA,vgamem_mb=128")
+    };
+    let machine = get_machine(config);
+    if needs_pci && machine.eq("isapc") {
+        return Err(Error::new(ErrorKind::MachineMismatch, format!("NIC {} needs a PCI bus, the {} machine does not have one", model, machine)));
     }
+    Ok(device)
 }
 
+const SOCKET_ENDPOINTS: [&str; 4] = ["listen", "connect", "mcast", "udp"];
 
-fn set_cdrom_cmd(config: &qemuconfig::QuickEmuConfig, cdrom: &String, cdrom_index: u8) -> String {
-    let cdrom_cmd: String = if cdrom.ne("") {
-        let mut index = cdrom_index;
-        if config.disk_interface.contains("ide") {
-            if config.disk_img.ne("") {
-                index = index + 1;
+pub fn check_network_backend(config: &qemuconfig::QuickEmuConfig) -> Result<(), Error> {
+    match config.network.as_str() {
+        "user" | "tap" | "bridge" | "none" => Ok(()),
+        "socket" => {
+            let extras = QemuArg::parse("netdev", &config.network_extras);
+            if SOCKET_ENDPOINTS.iter().all(|k| extras.get_prop(k).is_none()) {
+                return Err(Error::new(ErrorKind::UnknownNetworkDevice, "SOCKET NETWORK NEEDS listen=, connect=, mcast= or udp= IN network_extras"));
             }
-            if config.disk2_img.ne("") {
-                index = index + 1;
+            Ok(())
+        },
+        _ => Err(Error::new(ErrorKind::UnknownNetworkDevice, format!("NETWORK BACKEND {} IS UNKNOWN", config.network))),
+    }
+}
+
+fn set_network_cmd(config: &qemuconfig::QuickEmuConfig) -> Result<Vec<QemuArg>, Error>
+{
+    check_user_network(config)?;
+    check_network_backend(config)?;
+    let hostfwds = set_port_forwards(config)?;
+    //without any network options qemu adds a card of its own
+    if config.network.eq("none") {
+        return Ok(vec![QemuArg::with_value("nic", "none")]);
+    }
+    let device = get_nic_device(config)?;
+
+    let mut netdev = QemuArg::with_value("netdev", &config.network).prop("id", "net0");
+    netdev.append_props(&config.network_extras);
+    for hostfwd in hostfwds.iter() {
+        netdev = netdev.prop("hostfwd", hostfwd);
+    }
+    if !config.shared_folder.is_empty() {
+        netdev = netdev.prop("smb", &config.shared_folder);
+    }
+    if config.network.eq("bridge") && netdev.get_prop("br").is_none() {
+        netdev = netdev.prop("br", "br0");
+    }
+
+    Ok(vec![netdev, QemuArg::with_value("device", device).prop("netdev", "net0")])
+}
+
+const SERIAL_BASE_PORT: u16 = 4554;
+
+pub fn check_serial_port(i: usize, serial: &SerialPort) -> Result<(), Error> {
+    match serial.backend.as_str() {
+        "pty" | "stdio" | "null" | "unix" | "tcp" | "telnet" => Ok(()),
+        "file" if serial.path.is_empty() => {
+            Err(Error::new(ErrorKind::UnknownSerialBackend, format!("SERIAL PORT {} NEEDS A path FOR THE FILE BACKEND", i)))
+        },
+        "file" => Ok(()),
+        _ => Err(Error::new(ErrorKind::UnknownSerialBackend, format!("SERIAL BACKEND {} IS UNKNOWN", serial.backend))),
+    }
+}
+
+//each port is a chardev named serialN wired to the next guest uart
+fn set_serial_cmd(config: &qemuconfig::QuickEmuConfig, ports: &mut BTreeMap<String, u16>) -> Result<Vec<QemuArg>, Error>
+{
+    let mut args = Vec::new();
+    for (i, serial) in config.serial.iter().enumerate() {
+        check_serial_port(i, serial)?;
+        let id = format!("serial{}", i);
+        let chardev = match serial.backend.as_str() {
+            "pty" | "stdio" | "null" => QemuArg::with_value("chardev", &serial.backend).prop("id", &id),
+            "file" => QemuArg::with_value("chardev", "file").prop("id", &id).prop("path", &serial.path),
+            "unix" => {
+                let path = if serial.path.is_empty() {
+                    RuntimeDir::for_vm(&config.vmname)?.path.join(format!("{}.sock", id))
+                        .display().to_string()
+                } else {
+                    serial.path.clone()
+                };
+                QemuArg::with_value("chardev", "socket").prop("id", &id)
+                    .prop("path", &path)
+                    .prop("server", "on")
+                    .prop("wait", "off")
+            },
+            _ => {
+                let port = if serial.port == 0 {
+                    let taken: Vec<u16> = ports.values().cloned().collect();
+                    find_open_socket_excluding(SERIAL_BASE_PORT, &taken)?
+                } else {
+                    serial.port
+                };
+                ports.insert(id.clone(), port);
+                let chardev = QemuArg::with_value("chardev", "socket").prop("id", &id)
+                    .prop("host", "127.0.0.1")
+                    .prop("port", &port.to_string())
+                    .prop("server", "on")
+                    .prop("wait", "off");
+                if serial.backend.eq("telnet") {
+                    chardev.prop("telnet", "on")
+                } else {
+                    chardev
+                }
+            },
+        };
+        args.push(chardev);
+        args.push(QemuArg::with_value("serial", &format!("chardev:{}", id)));
+    }
+    Ok(args)
+}
+
+pub const SECURITY_MODELS: [&str; 4] = ["passthrough", "mapped-xattr", "mapped-file", "none"];
+
+pub fn check_share(share: &Share) -> Result<(), Error> {
+    match share.driver.as_str() {
+        "9p" => {
+            if !SECURITY_MODELS.contains(&share.security_model.as_str()) {
+                return Err(Error::new(ErrorKind::BadShare, format!("SECURITY MODEL {} IS UNKNOWN, USE ONE OF {}", share.security_model, SECURITY_MODELS.join(", "))));
             }
+            Ok(())
+        },
+        "virtiofs" => Ok(()),
+        _ => Err(Error::new(ErrorKind::BadShare, format!("SHARE DRIVER {} IS UNKNOWN, USE virtiofs OR 9p", share.driver))),
+    }
+}
+
+//the directory has to be there, and virtiofsd for a virtiofs share
+pub fn check_share_host(share: &Share) -> Result<(), Error> {
+    if !Path::new(&share.path).is_dir() {
+        return Err(Error::new(ErrorKind::NoSuchFile, format!("Share {} is not a directory!", share.path)));
+    }
+    if share.driver.eq("virtiofs") && find_binary("virtiofsd").is_none() {
+        return Err(Error::new(ErrorKind::MissingHelper, "virtiofsd was not found, install it or use driver = \"9p\""));
+    }
+    Ok(())
+}
+
+//a helper daemon by its full path when it is installed here, otherwise left to PATH
+fn helper_binary(name: &str) -> String {
+    match find_binary(name) {
+        Some(b) => b.display().to_string(),
+        None => name.to_string(),
+    }
+}
+
+//host directories for the guest, 9p is built into qemu, virtiofs needs virtiofsd running
+fn set_shares_cmd(config: &qemuconfig::QuickEmuConfig) -> Result<(Vec<QemuArg>, Vec<HelperProcess>), Error>
+{
+    let mut args = Vec::new();
+    let mut helpers = Vec::new();
+    for (i, share) in config.shares.iter().enumerate() {
+        check_share(share)?;
+        let tag = if share.tag.is_empty() {
+            format!("share{}", i)
+        } else {
+            share.tag.clone()
+        };
+        if share.driver.eq("9p") {
+                let mut virtfs = QemuArg::with_value("virtfs", "local")
+                    .prop("path", &share.path)
+                    .prop("mount_tag", &tag)
+                    .prop("security_model", &share.security_model)
+                    .prop("id", &format!("fs{}", i));
+                if share.readonly {
+                    virtfs = virtfs.prop("readonly", "on");
+                }
+                args.push(virtfs);
+        } else {
+                let socket = RuntimeDir::for_vm(&config.vmname)?.path
+                    .join(format!("virtiofs{}.sock", i))
+                    .display().to_string();
+                let mut daemon_args = vec![
+                    format!("--socket-path={}", socket),
+                    format!("--shared-dir={}", share.path),
+                ];
+                if share.readonly {
+                    daemon_args.push(String::from("--readonly"));
+                }
+                helpers.push(HelperProcess {
+                    name: format!("virtiofsd{}", i),
+                    binary: helper_binary("virtiofsd"),
+                    args: daemon_args,
+                    socket: Some(socket.clone()),
+                });
+                args.push(QemuArg::with_value("chardev", "socket")
+                    .prop("id", &format!("char-fs{}", i))
+                    .prop("path", &socket));
+                args.push(QemuArg::with_value("device", "vhost-user-fs-pci")
+                    .prop("chardev", &format!("char-fs{}", i))
+                    .prop("tag", &tag));
         }
-        format!("-drive media=cdrom,index={},file=\"{}\"", index, cdrom)
+    }
+    Ok((args, helpers))
+}
+
+fn set_display_cmd(config: &qemuconfig::QuickEmuConfig) -> Result<QemuArg, Error>
+{
+    let mut gl = if config.gl {
+        "on"
     } else {
-        format!("")
+        "off"
+    };
+    let mut output_extras = String::new();
+
+    if config.output == Output::Gtk
+    {
+        if gl.eq("on") {
+            gl = "es";
+        }
+        output_extras = String::from("grab-on-hover=on,zoom-to-fit=on");
+    }
+
+    if config.output == Output::Curses
+    {
+        gl = "off";
+    }
+
+    let mut display = QemuArg::with_value("display", config.output.as_str()).prop("gl", gl);
+    display.append_props(&output_extras);
+    display.append_props(&config.output_extras);
+    Ok(display)
+}
+
+fn set_audio_cmd(config: &qemuconfig::QuickEmuConfig) -> Result<Vec<QemuArg>, Error>
+{
+    let backend = config.audio_output.as_str();
+    let mut audiodev = QemuArg::with_value("audiodev", backend)
+        .prop("id", backend);
+    if config.audio_output == AudioOutput::Pa
+    {
+        let xdg = get_xdg_runtime_dir()?;
+        let stream = format!("{}-{}", config.launcher, config.vmname);
+        audiodev = audiodev.prop("server", &format!("unix:{}/pulse/native", xdg))
+            .prop("out.stream-name", &stream)
+            .prop("in.stream-name", &stream);
+    }
+
+    let device = match config.audio.device() {
+        Some(d) => d,
+        None => return Ok(Vec::new()),
     };
-    cdrom_cmd
+    let mut args = vec![audiodev];
+    //hda controllers need a codec, the codec is what gets the audio backend
+    if config.audio.is_hda() {
+        args.push(QemuArg::with_value("device", device));
+        args.push(QemuArg::with_value("device", "hda-duplex")
+            .prop("mixer", "off")
+            .prop("audiodev", backend));
+    } else {
+        args.push(QemuArg::with_value("device", device)
+            .prop("audiodev", backend));
+    }
+    Ok(args)
+}
+
+fn set_video_cmd(disp: DisplayDevice, virgl: &str) -> QemuArg {
+    let device = QemuArg::with_value;
+    match disp {
+        DisplayDevice::Cirrus => device("device", "cirrus-vga"),
+        DisplayDevice::IsaCirrus => device("device", "isa-cirrus-vga"),
+        DisplayDevice::Bochs => device("device", "bochs-display"),
+        DisplayDevice::Ati => device("device", "ati-vga"),
+        DisplayDevice::Vmware => device("device", "vmware-svga"),
+        DisplayDevice::Qxl => device("device", "qxl-vga"),
+        DisplayDevice::Virtio => device("device", "virtio-vga").prop("virgl", virgl),
+        DisplayDevice::IsaVga => device("device", "isa-vga"),
+        DisplayDevice::Vga => device("device", "VGA").prop("vgamem_mb", "128"),
+    }
+}
+
+
+//cdroms go on the ide bus after the ide disks
+fn set_cdrom_cmd(config: &qemuconfig::QuickEmuConfig, cdrom: &str, index: u8) -> Result<Vec<QemuArg>, Error> {
+    if cdrom.is_empty() {
+        return Ok(Vec::new());
+    }
+    if index >= get_ide_slots(config) {
+        return Err(Error::new(ErrorKind::TooManyDrives, format!("NO IDE SLOT LEFT FOR CDROM {}, MOVE SOME DISKS OFF IDE", cdrom)));
+    }
+    Ok(vec![QemuArg::flag("drive")
+        .prop("media", "cdrom")
+        .prop("index", &index.to_string())
+        .prop("file", cdrom)])
 }
 
-fn set_iso_file(iso: &str) -> Result<String, qemuconfig::ERRORCODES> {
-    if iso.ne("") {
+fn set_iso_file(iso: &str) -> Result<String, Error> {
+    if !iso.is_empty() {
         if Path::new(iso).exists()
         {
-            Ok(format!("{}", iso))
+            Ok(iso.to_string())
         } else {
-            error!("MISSING ISO FILE {}", iso);
-            Err(qemuconfig::ERRORCODES::NoSuchFile)
+            Err(Error::new(ErrorKind::NoSuchFile, format!("MISSING ISO FILE {}", iso)))
         }
     } else {
         Ok(String::from(""))
@@ -597,89 +1275,577 @@ fn set_iso_file(iso: &str) -> Result<String, qemuconfig::ERRORCODES> {
 }
 
 
-fn set_drive_cmd(config: &qemuconfig::QuickEmuConfig, disk_img: &String, drive_number: u8) -> Result<String, qemuconfig::ERRORCODES> {
-    let iface = if config.disk_interface.eq("") ||
-        config.disk_interface.eq("none") || config.disk_interface.contains("scsi")
-    {
-        "none"
-    } else
-    {
-        "ide"
+pub const DISK_CACHE_MODES: [&str; 5] = ["none", "writeback", "writethrough", "directsync", "unsafe"];
+pub const DISK_AIO_MODES: [&str; 3] = ["threads", "native", "io_uring"];
+pub const DISK_DISCARD_MODES: [&str; 4] = ["ignore", "unmap", "off", "on"];
+pub const DISK_DETECT_ZEROES_MODES: [&str; 3] = ["off", "on", "unmap"];
+
+//tmpfs and some fuse filesystems refuse O_DIRECT, which cache none and directsync use
+pub fn supports_o_direct(path: &Path) -> bool {
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return true,
     };
-    let mut drive_cmd: String = format!("-drive if={},id=drive{},cache=directsync,\
-    aio=native,format=qcow2,file=\"{}\"", iface, drive_number, disk_img);
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDONLY | libc::O_DIRECT) };
+    if fd < 0 {
+        //anything but EINVAL is for qemu to report
+        return std::io::Error::last_os_error().raw_os_error() != Some(libc::EINVAL);
+    }
+    unsafe {
+        libc::close(fd);
+    }
+    true
+}
 
-    if config.disk_interface.eq("") || config.disk_interface.eq("none") || config.disk_interface.contains("ide")
-    {
-        let res: String = format!("{} -device virtio-blk-pci,drive=drive{},scsi=off", drive_cmd, drive_number);
-        Ok(res)
-    } else if config.disk_interface.contains("scsi") {
-        if config.scsi_controller.ne("")
-        {
-            if drive_number == 0 {
-                drive_cmd = format!("-device {} {}", config.scsi_controller, drive_cmd);
-            }
-            Ok(format!("{} -device scsi-hd,drive=drive{}", drive_cmd, drive_number))
-        } else {
-            let e = "SCSI CONTROLLER TYPE WAS NOT DEFINED!";
-            error!("{}", e);
-            Err(qemuconfig::ERRORCODES::ScsiControllerMissing)
+fn check_disk_option(disk: &Disk, name: &str, value: &str, allowed: &[&str]) -> Result<(), Error> {
+    if !value.is_empty() && !allowed.contains(&value) {
+        return Err(Error::new(ErrorKind::BadDiskOption, format!("DISK {} {} {} IS UNKNOWN, USE ONE OF {}", disk.path, name, value, allowed.join(", "))));
+    }
+    Ok(())
+}
+
+//native aio needs O_DIRECT, which only these cache modes use
+fn is_direct_cache(cache: &str) -> bool {
+    cache.eq("none") || cache.eq("directsync")
+}
+
+pub fn check_cache_aio(disk: &Disk) -> Result<(), Error> {
+    check_disk_option(disk, "cache", &disk.cache, &DISK_CACHE_MODES)?;
+    check_disk_option(disk, "aio", &disk.aio, &DISK_AIO_MODES)?;
+    if disk.aio.eq("native") && !is_direct_cache(&disk.cache) {
+        return Err(Error::new(ErrorKind::BadDiskOption, format!("DISK {} aio native NEEDS cache none OR directsync", disk.path)));
+    }
+    Ok(())
+}
+
+//when the image's filesystem can't do O_DIRECT the direct modes fall back to their buffered versions, with the same write guarantees
+fn o_direct_fallback(disk: &Disk) -> Option<&'static str> {
+    let fallback = match disk.cache.as_str() {
+        "none" => "writeback",
+        "directsync" => "writethrough",
+        _ => return None,
+    };
+    if supports_o_direct(Path::new(&disk.path)) { None } else { Some(fallback) }
+}
+
+//what the command line quietly changes or leaves out for a disk
+pub fn get_disk_warnings(config: &qemuconfig::QuickEmuConfig, disk: &Disk) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Some(cache) = o_direct_fallback(disk) {
+        warnings.push(format!("{} is on a filesystem without O_DIRECT, using cache={} instead of {}", disk.path, cache, disk.cache));
+        if disk.aio.eq("native") {
+            warnings.push(format!("{} can't use aio=native without O_DIRECT, using threads", disk.path));
         }
+    }
+    //-drive if=ide has no serial, the ahci interface does
+    if !disk.serial.is_empty() && get_disk_interface(config, disk).is_ide() {
+        warnings.push(format!("{} is on ide, which can't have a serial, use ahci for that", disk.path));
+    }
+    warnings
+}
+
+//the cache and aio modes a disk ends up with, native aio is the default with O_DIRECT
+fn get_cache_aio(disk: &Disk) -> Result<(String, String), Error> {
+    check_cache_aio(disk)?;
+    let cache = match o_direct_fallback(disk) {
+        Some(c) => c.to_string(),
+        None => disk.cache.clone(),
+    };
+    let direct = is_direct_cache(&cache);
+    let aio = if disk.aio.is_empty() {
+        String::from(if direct { "native" } else { "threads" })
+    } else if disk.aio.eq("native") && !direct {
+        String::from("threads")
     } else {
-        let e = format!("DISK CONTROLLER TYPE {} IS UNKNOWN", config.disk_interface);
-        error!("{}", e);
-        Err(qemuconfig::ERRORCODES::UnknownDiskController)
+        disk.aio.clone()
+    };
+    Ok((cache, aio))
+}
+
+pub fn check_drive_tuning(disk: &Disk) -> Result<(), Error> {
+    check_disk_option(disk, "discard", &disk.discard, &DISK_DISCARD_MODES)?;
+    check_disk_option(disk, "detect_zeroes", &disk.detect_zeroes, &DISK_DETECT_ZEROES_MODES)?;
+    let unmaps = disk.discard.eq("unmap") || disk.discard.eq("on");
+    if disk.detect_zeroes.eq("unmap") && !unmaps {
+        return Err(Error::new(ErrorKind::BadDiskOption, format!("DISK {} detect_zeroes unmap NEEDS discard unmap", disk.path)));
+    }
+    Ok(())
+}
+
+//discard, zero detection and throttling, as -drive properties
+fn get_drive_tuning(disk: &Disk) -> Result<Vec<(&'static str, String)>, Error> {
+    check_drive_tuning(disk)?;
+    let mut props = Vec::new();
+    if !disk.discard.is_empty() {
+        props.push(("discard", disk.discard.clone()));
     }
+    if !disk.detect_zeroes.is_empty() {
+        props.push(("detect-zeroes", disk.detect_zeroes.clone()));
+    }
+    let limits = [
+        ("throttling.iops-total", disk.iops),
+        ("throttling.iops-read", disk.iops_rd),
+        ("throttling.iops-write", disk.iops_wr),
+        ("throttling.bps-total", disk.bps),
+        ("throttling.bps-read", disk.bps_rd),
+        ("throttling.bps-write", disk.bps_wr),
+    ];
+    for (key, limit) in limits.iter() {
+        if *limit > 0 {
+            props.push((*key, limit.to_string()));
+        }
+    }
+    Ok(props)
 }
 
+//ide on pc and isapc has two buses with two drives each, q35's ahci has six ports
+pub fn get_ide_slots(config: &qemuconfig::QuickEmuConfig) -> u8 {
+    if get_machine(config).eq("q35") { 6 } else { 4 }
+}
 
-fn handle_disk_image(qemu_img_path: &str, disk_img: &str, disk_size: &str) -> String {
-        if disk_img.ne("") {
-            if !Path::new(disk_img).exists() {
-                //make disk image
-                debug!("{} is imger", qemu_img_path);
+pub fn get_disk_interface(config: &qemuconfig::QuickEmuConfig, disk: &Disk) -> DiskInterface {
+    disk.interface.unwrap_or(config.disk_interface)
+}
 
-                let r = Command::new(qemu_img_path)
-                    .args(&["create", "-q", "-f", "qcow2",disk_img,disk_size])
-                    .output()
-                    .expect("Failed to make disk image");
-                debug!("e {}",r.status);
-                debug!("stdout: {}", String::from_utf8_lossy(&r.stdout));
-                debug!("stdout: {}", String::from_utf8_lossy(&r.stderr))
+//ahci has six ports, one disk each
+pub const AHCI_PORTS: u8 = 6;
+//ide, scsi, nvme and virtio-blk all cut serials off at 20 characters
+pub const DISK_SERIAL_MAX: usize = 20;
 
-            } else {
-                debug!("Image {} seems to exist, skipping creation!",disk_img);
+//nvme needs a serial, the others only get one when the disk sets it
+pub fn get_disk_serial(disk: &Disk, n: usize, required: bool) -> Result<Option<String>, Error> {
+    if disk.serial.len() > DISK_SERIAL_MAX {
+        return Err(Error::new(ErrorKind::BadDiskOption, format!("DISK {} SERIAL {} IS LONGER THAN {} CHARACTERS", disk.path, disk.serial, DISK_SERIAL_MAX)));
+    }
+    if !disk.serial.is_empty() {
+        Ok(Some(disk.serial.clone()))
+    } else if required {
+        Ok(Some(format!("drive{}", n)))
+    } else {
+        Ok(None)
+    }
+}
+
+//parallel scsi has 8 ids and the controller takes the last one
+const PARALLEL_SCSI_CONTROLLERS: [&str; 4] = ["lsi", "lsi53c895a", "lsi53c810", "am53c974"];
+
+//the scsi_controller device with an id, and how many disks it takes
+pub fn get_scsi_controller(config: &qemuconfig::QuickEmuConfig) -> Result<(QemuArg, String, u8), Error> {
+    if config.scsi_controller.is_empty() {
+        return Err(Error::new(ErrorKind::ScsiControllerMissing, "SCSI CONTROLLER TYPE WAS NOT DEFINED!"));
+    }
+    let mut controller = QemuArg::parse("device", &config.scsi_controller);
+    let id = match controller.get_prop("id") {
+        Some(id) => id.to_string(),
+        None => {
+            controller = controller.prop("id", "scsi0");
+            String::from("scsi0")
+        }
+    };
+    let parallel = controller.value.as_deref().map(|v| PARALLEL_SCSI_CONTROLLERS.contains(&v)).unwrap_or(false);
+    let limit = if parallel { 7 } else { u8::MAX };
+    Ok((controller, id, limit))
+}
+
+//whether a disk's interface fits the machine and the controllers the vm has
+pub fn check_disk_interface(config: &qemuconfig::QuickEmuConfig, disk: &Disk) -> Result<(), Error> {
+    let iface = get_disk_interface(config, disk);
+    if iface.is_pci() && get_machine(config).eq("isapc") {
+        return Err(Error::new(ErrorKind::MachineMismatch, format!("THE isapc MACHINE HAS NO PCI BUS FOR {} DISK {}", iface, disk.path)));
+    }
+    match iface {
+        DiskInterface::Ahci | DiskInterface::Ide | DiskInterface::IsaIde if disk.readonly => {
+            let bus = if iface.is_ide() { "IDE" } else { "AHCI" };
+            Err(Error::new(ErrorKind::BadDiskOption, format!("{} DISKS CAN'T BE READ ONLY, PUT {} ON virtio OR scsi", bus, disk.path)))
+        },
+        //qemu chains hubs on by itself when the controller runs out of ports
+        DiskInterface::UsbStorage if get_usb_controller(config).eq("none") => {
+            Err(Error::new(ErrorKind::UnknownUsbController, format!("usb-storage DISK {} NEEDS A usb_controller", disk.path)))
+        },
+        DiskInterface::Scsi => get_scsi_controller(config).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+//a controller the disks on it share, added before its first disk
+fn add_controller(args: &mut Vec<QemuArg>, added: &mut bool, device: &str, id: &str) {
+    if !*added {
+        args.push(QemuArg::with_value("device", device).prop("id", id));
+        *added = true;
+    }
+}
+
+/*
+ * Every disk gets drive<N> as its id. IDE disks take the ide indices in
+ * order, the cdroms get the ones after them, which is why the next free
+ * index is handed back. SCSI disks share one controller and get a scsi-id
+ * each, so do virtio-scsi disks on their own controller. AHCI disks get a
+ * port each on one controller, NVMe disks a controller each, and
+ * usb-storage disks go on the usb controller. Their devices come back
+ * separately, as they have to follow the usb controller on the command line.
+ */
+fn set_drive_cmd(config: &qemuconfig::QuickEmuConfig, disks: &[Disk]) -> Result<(Vec<QemuArg>, Vec<QemuArg>, u8), Error> {
+    let mut args = Vec::new();
+    let mut usb_args = Vec::new();
+    let mut ide_index = 0u8;
+    let mut scsi_bus: Option<(String, u8)> = None; //controller id and scsi-id limit
+    let mut scsi_id = 0u8;
+    let (mut ahci_added, mut ahci_port) = (false, 0u8);
+    let (mut vscsi_added, mut vscsi_id) = (false, 0u8);
+    for (n, disk) in disks.iter().enumerate() {
+        let iface = get_disk_interface(config, disk);
+        let (cache, aio) = get_cache_aio(disk)?;
+        let tuning = get_drive_tuning(disk)?;
+        let serial = get_disk_serial(disk, n, iface == DiskInterface::Nvme)?;
+        check_disk_interface(config, disk)?;
+        //a real launch has warned about these in the validator
+        for warning in get_disk_warnings(config, disk) {
+            debug!("{}", warning);
+        }
+        let drive_id = format!("drive{}", n);
+        let drive = |bus: &str| {
+            let mut d = QemuArg::flag("drive")
+                .prop("if", bus)
+                .prop("id", &drive_id)
+                .prop("cache", &cache)
+                .prop("aio", &aio)
+                .prop("format", &disk.format)
+                .prop("file", &disk.path);
+            for (key, value) in tuning.iter() {
+                d = d.prop(key, value);
             }
-            format!("{}", disk_img)
-        } else {
-            debug!("Disk Image was not set.");
-            format!("")
+            if disk.readonly { d.prop("readonly", "on") } else { d }
+        };
+        let device = |name: &str| {
+            let d = QemuArg::with_value("device", name).prop("drive", &drive_id);
+            match &serial {
+                Some(s) => d.prop("serial", s),
+                None => d,
+            }
+        };
+
+        match iface {
+            DiskInterface::Virtio => {
+                args.push(drive("none"));
+                args.push(device("virtio-blk-pci").prop("scsi", "off"));
+            },
+            DiskInterface::Nvme => {
+                args.push(drive("none"));
+                args.push(device("nvme"));
+            },
+            DiskInterface::Ahci => {
+                if ahci_port >= AHCI_PORTS {
+                    return Err(Error::new(ErrorKind::TooManyDrives, format!("NO AHCI PORT LEFT FOR {}", disk.path)));
+                }
+                add_controller(&mut args, &mut ahci_added, "ahci", "ahci0");
+                args.push(drive("none"));
+                args.push(device("ide-hd").prop("bus", &format!("ahci0.{}", ahci_port)));
+                ahci_port += 1;
+            },
+            DiskInterface::VirtioScsi => {
+                if vscsi_id == u8::MAX {
+                    return Err(Error::new(ErrorKind::TooManyDrives, format!("NO SCSI ID LEFT FOR {} ON virtio-scsi", disk.path)));
+                }
+                add_controller(&mut args, &mut vscsi_added, "virtio-scsi-pci", "vscsi0");
+                args.push(drive("none"));
+                args.push(device("scsi-hd")
+                    .prop("bus", "vscsi0.0")
+                    .prop("scsi-id", &vscsi_id.to_string())
+                    .prop("lun", "0"));
+                vscsi_id += 1;
+            },
+            DiskInterface::UsbStorage => {
+                args.push(drive("none"));
+                usb_args.push(device("usb-storage").prop("bus", "usb.0"));
+            },
+            DiskInterface::Ide | DiskInterface::IsaIde => {
+                if ide_index >= get_ide_slots(config) {
+                    return Err(Error::new(ErrorKind::TooManyDrives, format!("NO IDE SLOT LEFT FOR {}", disk.path)));
+                }
+                args.push(drive("ide").prop("index", &ide_index.to_string()));
+                ide_index += 1;
+            },
+            DiskInterface::Scsi => {
+                if scsi_bus.is_none() {
+                    let (controller, id, limit) = get_scsi_controller(config)?;
+                    args.push(controller);
+                    scsi_bus = Some((id, limit));
+                }
+                let (bus, limit) = scsi_bus.as_ref().unwrap();
+                if scsi_id >= *limit {
+                    return Err(Error::new(ErrorKind::TooManyDrives, format!("NO SCSI ID LEFT FOR {} ON {}", disk.path, config.scsi_controller)));
+                }
+                args.push(drive("none"));
+                args.push(device("scsi-hd")
+                    .prop("bus", &format!("{}.0", bus))
+                    .prop("scsi-id", &scsi_id.to_string()));
+                scsi_id += 1;
+            },
+        }
+    }
+    Ok((args, usb_args, ide_index))
+}
+
+/*
+ * A linked clone: a qcow2 image that only holds what changed since the
+ * base image. The base must not change afterwards, it is written down with
+ * an absolute path so the overlay can be moved.
+ */
+fn get_overlay_base(disk: &Disk) -> Result<(String, String), Error> {
+    let base = match fs::canonicalize(&disk.base_image) {
+        Ok(b) => b,
+        Err(e) => {
+            return Err(Error::new(ErrorKind::NoSuchFile, format!("Base image {} of {}", disk.base_image, disk.path)).with_source(e));
+        }
+    };
+    let base_format = match diskformat::detect_format(&base) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(Error::new(ErrorKind::NoSuchFile, format!("Could not read {}", base.display())).with_source(e));
+        }
+    };
+    Ok((base.display().to_string(), base_format))
+}
+
+//an existing image is whatever its header says, a format set in the config has to agree with that
+pub fn get_existing_disk_format(disk: &Disk) -> Result<String, Error> {
+    let detected = match diskformat::detect_format(Path::new(&disk.path)) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(Error::new(ErrorKind::NoSuchFile, format!("Could not read {}", disk.path)).with_source(e));
+        }
+    };
+    debug!("{} looks like {}", disk.path, detected);
+    if disk.format.is_empty() {
+        Ok(detected)
+    } else if disk.format.ne(&detected) && diskformat::is_detectable(&disk.format) {
+        Err(Error::new(ErrorKind::DiskFormat, format!("{} is a {} image but its format is set to {}", disk.path, detected, disk.format)))
+    } else {
+        Ok(disk.format.clone())
+    }
+}
+
+//a missing image is created in the configured format, or the one its extension implies, and the two have to agree or the file would be misnamed
+pub fn get_new_disk_format(disk: &Disk) -> Result<String, Error> {
+    if disk.readonly {
+        return Err(Error::new(ErrorKind::NoSuchFile, format!("Read only disk {} does not exist!", disk.path)));
+    }
+    let by_extension = diskformat::format_for_extension(Path::new(&disk.path));
+    if !disk.base_image.is_empty() {
+        if !Path::new(&disk.base_image).exists() {
+            return Err(Error::new(ErrorKind::NoSuchFile, format!("Base image {} of {} does not exist", disk.base_image, disk.path)));
+        }
+        if (!disk.format.is_empty() && disk.format.ne("qcow2")) || by_extension.map(|f| f.ne("qcow2")).unwrap_or(false) {
+            return Err(Error::new(ErrorKind::DiskFormat, format!("Refusing to create {}: overlays of {} have to be qcow2", disk.path, disk.base_image)));
         }
+        return Ok(String::from("qcow2"));
+    }
+    if disk.format.is_empty() {
+        Ok(by_extension.unwrap_or("qcow2").to_string())
+    } else if let Some(ext_format) = by_extension.filter(|f| disk.format.ne(f)) {
+        Err(Error::new(ErrorKind::DiskFormat, format!("Refusing to create {}: its extension says {} but its format is set to {}", disk.path, ext_format, disk.format)))
+    } else {
+        Ok(disk.format.clone())
+    }
+}
+
+//settle the format of a disk, and the step that creates the image when it is missing
+pub fn get_disk_setup(qemu_img_path: &str, disk: &Disk) -> Result<(Disk, Option<SetupStep>), Error> {
+    let mut disk = disk.clone();
+    if Path::new(&disk.path).exists() {
+        disk.format = get_existing_disk_format(&disk)?;
+        return Ok((disk, None));
+    }
+    disk.format = get_new_disk_format(&disk)?;
+    let mut argv: Vec<String> = [qemu_img_path, "create", "-q", "-f", &disk.format].iter().map(|a| a.to_string()).collect();
+    if disk.base_image.is_empty() {
+        argv.push(disk.path.clone());
+        argv.push(disk.size.clone());
+    } else {
+        let (base, base_format) = get_overlay_base(&disk)?;
+        argv.extend(vec![String::from("-b"), base, String::from("-F"), base_format, disk.path.clone()]);
+    }
+    let step = SetupStep::CreateImage { path: disk.path.clone(), argv };
+    Ok((disk, Some(step)))
+}
+
+//settle the format of a disk, creating the image when it is missing
+pub fn handle_disk_image(qemu_img_path: &str, disk: &Disk) -> Result<Disk, Error> {
+    let (disk, step) = get_disk_setup(qemu_img_path, disk)?;
+    if let Some(step) = step {
+        step.run()?;
+    }
+    Ok(disk)
 }
 
-fn set_boot_menu(config: &qemuconfig::QuickEmuConfig) -> String {
-    let boot_menu = if config.boot_menu == true {
-        format!("-boot menu=on")
+fn set_boot_menu(config: &qemuconfig::QuickEmuConfig) -> QemuArg {
+    if config.boot_menu {
+        QemuArg::flag("boot").prop("menu", "on")
     } else {
-        format!("-boot menu=off")
+        QemuArg::flag("boot").prop("menu", "off")
+    }
+}
+
+//where files that belong to the vm, like its uefi variables, are kept: next to its first disk or its config
+pub fn get_vm_dir(config: &qemuconfig::QuickEmuConfig) -> PathBuf {
+    match config.disks.first().and_then(|d| Path::new(&d.path).parent()) {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => config.config_dir.clone(),
+    }
+}
+
+pub fn is_efi(config: &qemuconfig::QuickEmuConfig) -> Result<bool, Error> {
+    match config.boot {
+        Boot::Auto => Ok(config.secure_boot),
+        Boot::Legacy if config.secure_boot => {
+            Err(Error::new(ErrorKind::UnknownBootMode, "SECURE BOOT NEEDS boot = \"efi\""))
+        },
+        Boot::Legacy => Ok(false),
+        Boot::Efi => Ok(true),
+    }
+}
+
+//whether the vm boots uefi, which the machine has to be able to do
+pub fn check_efi(config: &qemuconfig::QuickEmuConfig) -> Result<bool, Error> {
+    if !is_efi(config)? {
+        return Ok(false);
+    }
+    let machine = get_machine(config);
+    if machine.eq("isapc") {
+        return Err(Error::new(ErrorKind::MachineMismatch, "THE isapc MACHINE CAN'T BOOT EFI"));
+    }
+    if config.secure_boot && machine.ne("q35") {
+        return Err(Error::new(ErrorKind::MachineMismatch, format!("SECURE BOOT NEEDS THE q35 MACHINE, NOT {}", machine)));
+    }
+    Ok(true)
+}
+
+//an efi vm needs an OVMF build installed
+pub fn check_ovmf(config: &qemuconfig::QuickEmuConfig) -> Result<(), Error> {
+    if !check_efi(config)? {
+        return Ok(());
+    }
+    let machine = get_machine(config);
+    match firmware::find_ovmf(&machine, config.secure_boot) {
+        Some(_) => Ok(()),
+        None => Err(Error::new(ErrorKind::MissingFirmware, format!("No {}OVMF firmware found for {}, install ovmf (or edk2-ovmf)", if config.secure_boot { "secure boot " } else { "" }, machine))),
+    }
+}
+
+/*
+ * UEFI boot from two pflash drives: the shared read only OVMF code and a
+ * copy of the variable store the vm can write its boot entries to. The
+ * copy is made from the firmware's template on first boot. Without OVMF
+ * here the usual paths are used, for a command run on another host.
+ */
+fn set_efi_cmd(config: &qemuconfig::QuickEmuConfig, setup: &mut Vec<SetupStep>) -> Result<Vec<QemuArg>, Error>
+{
+    if !check_efi(config)? {
+        return Ok(Vec::new());
+    }
+    let machine = get_machine(config);
+    let fw = firmware::find_ovmf(&machine, config.secure_boot)
+        .unwrap_or_else(|| firmware::default_ovmf(config.secure_boot));
+    debug!("OVMF code {} vars {}", fw.code, fw.vars_template);
+    let ext = if fw.format.eq("raw") { "fd" } else { fw.format.as_str() };
+    //the two firmwares' variable stores don't mix, each gets its own copy
+    let suffix = if config.secure_boot { "-secboot" } else { "" };
+    let vars = get_vm_dir(config).join(format!("{}-efivars{}.{}", config.vmname, suffix, ext));
+    setup.push(SetupStep::CopyFile {
+        from: fw.vars_template.clone(),
+        to: vars.display().to_string(),
+    });
+    let mut args = vec![
+        QemuArg::flag("drive")
+            .prop("if", "pflash")
+            .prop("format", &fw.format)
+            .prop("unit", "0")
+            .prop("file", &fw.code)
+            .prop("readonly", "on"),
+        QemuArg::flag("drive")
+            .prop("if", "pflash")
+            .prop("format", &fw.format)
+            .prop("unit", "1")
+            .prop("file", &vars.display().to_string()),
+    ];
+    if fw.has_feature("requires-smm") {
+        args.push(QemuArg::flag("global")
+            .prop("driver", "cfi.pflash01")
+            .prop("property", "secure")
+            .prop("value", "on"));
+    }
+    Ok(args)
+}
+
+pub fn check_tpm(config: &qemuconfig::QuickEmuConfig) -> Result<(), Error> {
+    if config.tpm && get_machine(config).eq("isapc") {
+        return Err(Error::new(ErrorKind::MachineMismatch, "THE isapc MACHINE CAN'T HAVE A TPM"));
+    }
+    Ok(())
+}
+
+pub fn check_swtpm(config: &qemuconfig::QuickEmuConfig) -> Result<(), Error> {
+    if config.tpm && find_binary("swtpm").is_none() {
+        return Err(Error::new(ErrorKind::MissingSwtpm, "swtpm was not found, install swtpm to use tpm"));
+    }
+    Ok(())
+}
+
+/*
+ * A tpm 2.0 emulated by swtpm, which qemu talks to over a socket in the
+ * runtime dir. The tpm state lives next to the disk image so it survives
+ * reboots, windows keeps bitlocker keys in it.
+ */
+fn set_tpm_cmd(config: &qemuconfig::QuickEmuConfig, setup: &mut Vec<SetupStep>) -> Result<(Vec<QemuArg>, Option<HelperProcess>), Error>
+{
+    check_tpm(config)?;
+    if !config.tpm {
+        return Ok((Vec::new(), None));
+    }
+    let machine = get_machine(config);
+    let state = get_vm_dir(config).join(format!("{}-tpm", config.vmname));
+    setup.push(SetupStep::CreateDir { path: state.display().to_string() });
+    let socket = RuntimeDir::for_vm(&config.vmname)?.path.join("swtpm.sock").display().to_string();
+    let helper = HelperProcess {
+        name: String::from("swtpm"),
+        binary: helper_binary("swtpm"),
+        args: vec![
+            String::from("socket"),
+            String::from("--tpm2"),
+            format!("--tpmstate=dir={}", state.display()),
+            format!("--ctrl=type=unixio,path={}", socket),
+            //exit with qemu instead of lingering
+            String::from("--terminate"),
+        ],
+        socket: Some(socket.clone()),
     };
-    boot_menu
+    //the crb interface is what q35 machines and windows 11 expect
+    let device = if machine.eq("q35") { "tpm-crb" } else { "tpm-tis" };
+    let args = vec![
+        QemuArg::with_value("chardev", "socket").prop("id", "chrtpm").prop("path", &socket),
+        QemuArg::with_value("tpmdev", "emulator").prop("id", "tpm0").prop("chardev", "chrtpm"),
+        QemuArg::with_value("device", device).prop("tpmdev", "tpm0"),
+    ];
+    Ok((args, Some(helper)))
 }
 
-fn set_floppy(config: &qemuconfig::QuickEmuConfig) -> Result<String, qemuconfig::ERRORCODES> {
-    if config.floppy.ne("") {
+fn set_floppy(config: &qemuconfig::QuickEmuConfig) -> Result<Vec<QemuArg>, Error> {
+    if !config.floppy.is_empty() {
         if Path::new(config.floppy.as_str()).exists() {
-            Ok(format!("-fda {}", config.floppy))
+            Ok(vec![QemuArg::flag("drive")
+                .prop("if", "floppy")
+                .prop("index", "0")
+                .prop("file", &config.floppy)])
         } else {
-            error!("File {} does not seem to exist!", config.floppy);
-            Err(qemuconfig::ERRORCODES::NoSuchFile)
+            Err(Error::new(ErrorKind::NoSuchFile, format!("File {} does not seem to exist!", config.floppy)))
         }
     } else {
-        Ok(format!(""))
+        Ok(Vec::new())
     }
 }
 
-fn set_ram_value(config: &qemuconfig::QuickEmuConfig) -> String {
+//-m and the memfd backend read a plain number differently, MiB and bytes, so both get a unit
+fn set_ram_value(config: &qemuconfig::QuickEmuConfig) -> Result<Vec<QemuArg>, Error> {
     let ram = if config.ram.eq("auto") {
         let m = utils::get_system_memory() / 1_000_000;
         if m >= 64 {
@@ -690,13 +1856,53 @@ fn set_ram_value(config: &qemuconfig::QuickEmuConfig) -> String {
             format!("{}G", 2u8)
         }
     } else {
-        format!("{}", config.ram)
+        match get_ram_size(&config.ram)? {
+            bytes if bytes % (1 << 30) == 0 => format!("{}G", bytes >> 30),
+            bytes if bytes % (1 << 20) == 0 => format!("{}M", bytes >> 20),
+            bytes => format!("{}K", bytes >> 10),
+        }
     };
-    ram
+    let mut args = vec![QemuArg::with_value("m", &ram)];
+    //virtiofsd maps guest ram, so it has to live in shared memory
+    if config.shares.iter().any(|s| s.driver.eq("virtiofs")) {
+        args.push(QemuArg::with_value("object", "memory-backend-memfd")
+            .prop("id", "mem")
+            .prop("size", &ram)
+            .prop("share", "on"));
+        args.push(QemuArg::with_value("numa", "node").prop("memdev", "mem"));
+    }
+    Ok(args)
 }
 
+pub fn get_ram_size(ram: &str) -> Result<u64, Error> {
+    match parse_ram(ram) {
+        Some(bytes) => Ok(bytes),
+        None => Err(Error::new(ErrorKind::InvalidConfig, format!("RAM {} IS NOT A SIZE, USE e.g. 4G OR 4096M", ram))),
+    }
+}
+
+//qemu's -m size, a plain number is MiB, a unit may have a B after it but a B alone isn't one
+fn parse_ram(ram: &str) -> Option<u64> {
+    let ram = ram.trim();
+    let split = ram.find(|c: char| !c.is_ascii_digit()).unwrap_or(ram.len());
+    let (number, unit) = ram.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let shift = match unit.to_uppercase().as_str() {
+        "K" | "KB" => 10,
+        "" | "M" | "MB" => 20,
+        "G" | "GB" => 30,
+        "T" | "TB" => 40,
+        _ => return None,
+    };
+    if number == 0 {
+        return None;
+    }
+    number.checked_mul(1 << shift)
+}
+
+
 fn set_cpu_cores(config: &qemuconfig::QuickEmuConfig) -> u8 {
-    let cpu_cores = if config.cpu_cores == 0 {
+    if config.cpu_cores == 0 {
         if num_cpus::get_physical() >= 8 {
             4u8
         } else {
@@ -704,7 +1910,37 @@ fn set_cpu_cores(config: &qemuconfig::QuickEmuConfig) -> u8 {
         }
     } else {
         config.cpu_cores
-    };
-    cpu_cores
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_units() {
+        assert_eq!(parse_ram("512"), Some(512 << 20));
+        assert_eq!(parse_ram("512M"), Some(512 << 20));
+        assert_eq!(parse_ram("4G"), Some(4 << 30));
+        assert_eq!(parse_ram("4gb"), Some(4 << 30));
+        assert_eq!(parse_ram(" 65536K "), Some(64 << 20));
+        assert_eq!(parse_ram("1T"), Some(1 << 40));
+    }
+
+    #[test]
+    fn ram_that_is_not_a_size() {
+        for ram in ["", "B", "100B", "4GBB", "4GiB", "G", "-1G", "4.5G", "0", "0G", "lots"].iter() {
+            assert_eq!(parse_ram(ram), None, "{:?}", ram);
+        }
+        assert_eq!(get_ram_size("100B").unwrap_err().kind(), ErrorKind::InvalidConfig);
+    }
+
+    #[test]
+    fn ram_that_overflows() {
+        //shifting never notices, these used to wrap to small sizes
+        assert_eq!(parse_ram("16777216T"), None);
+        assert_eq!(parse_ram("18446744073709551615K"), None);
+        assert_eq!(parse_ram("18446744073709551616"), None);
+        assert_eq!(parse_ram("16777215T"), Some(16777215 << 40));
+    }
+}
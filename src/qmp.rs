@@ -0,0 +1,409 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * Minimal client for the QEMU Machine Protocol.
+ * QMP is line delimited JSON over the unix socket every vm gets in its
+ * runtime dir. After the greeting the client has to send qmp_capabilities
+ * before any other command. Asynchronous events can arrive at any point,
+ * including between a command and its reply, so they are queued.
+ */
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+use crate::error::{self, Error};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug)]
+pub struct StatusInfo {
+    pub status: String,
+    pub running: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "vm-state-size")]
+    pub vm_state_size: u64,
+    #[serde(rename = "date-sec")]
+    pub date_sec: i64,
+    #[serde(rename = "vm-clock-sec")]
+    pub vm_clock_sec: i64,
+    #[serde(rename = "vm-clock-nsec")]
+    pub vm_clock_nsec: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImageInfo {
+    #[serde(default)]
+    pub snapshots: Vec<SnapshotInfo>,
+    #[serde(default)]
+    pub format: String,
+    #[serde(rename = "virtual-size", default)]
+    pub virtual_size: u64,
+    #[serde(rename = "actual-size", default)]
+    pub actual_size: u64,
+    #[serde(rename = "full-backing-filename")]
+    pub backing_file: Option<String>,
+    #[serde(rename = "backing-filename-format")]
+    pub backing_format: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockInserted {
+    pub file: String,
+    pub ro: bool,
+    pub drv: String,
+    pub image: Option<ImageInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockInfo {
+    pub device: String,
+    #[serde(default)]
+    pub qdev: String,
+    pub removable: bool,
+    #[serde(default)]
+    pub locked: bool,
+    pub inserted: Option<BlockInserted>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChardevInfo {
+    pub label: String,
+    pub filename: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventTimestamp {
+    pub seconds: i64,
+    pub microseconds: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct QmpEvent {
+    pub event: String,
+    #[serde(default)]
+    pub data: Value,
+    pub timestamp: EventTimestamp,
+}
+
+pub struct QmpClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    events: VecDeque<QmpEvent>,
+}
+
+fn qmp_error(msg: String) -> Error {
+    Error::new(error::ErrorKind::Qmp, format!("QMP: {}", msg))
+}
+
+impl QmpClient {
+    //connect and do the capabilities handshake
+    pub fn connect(socket: &Path) -> Result<QmpClient, Error> {
+        let stream = UnixStream::connect(socket)
+            .map_err(|e| qmp_error(format!("could not connect to {}: {}", socket.display(), e)))?;
+        let writer = stream.try_clone()
+            .map_err(|e| qmp_error(format!("{}", e)))?;
+        let mut client = QmpClient {
+            reader: BufReader::new(stream),
+            writer,
+            events: VecDeque::new(),
+        };
+        client.set_timeout(Some(DEFAULT_TIMEOUT))?;
+
+        let greeting = client.read_message()?
+            .ok_or_else(|| qmp_error(String::from("no greeting from qemu")))?;
+        match greeting.get("QMP") {
+            Some(g) => debug!("QMP greeting {}", g),
+            None => return Err(qmp_error(format!("unexpected greeting {}", greeting))),
+        }
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.writer.set_read_timeout(timeout)
+            .map_err(|e| qmp_error(format!("{}", e)))
+    }
+
+    //one json message, None on timeout
+    fn read_message(&mut self) -> Result<Option<Value>, Error> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => {
+                //qemu closes the socket when it exits, callers decide if that is a problem
+                debug!("QMP: connection closed by qemu");
+                Err(qmp_error(String::from("connection closed by qemu")))
+            },
+            Ok(_) => {
+                trace!("QMP <- {}", line.trim());
+                serde_json::from_str(&line)
+                    .map(Some)
+                    .map_err(|e| qmp_error(format!("bad message {}: {}", line.trim(), e)))
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(qmp_error(format!("{}", e))),
+        }
+    }
+
+    fn queue_event(&mut self, msg: Value) {
+        match serde_json::from_value::<QmpEvent>(msg) {
+            Ok(ev) => self.events.push_back(ev),
+            Err(e) => warn!("QMP: dropping malformed event: {}", e),
+        }
+    }
+
+    //run a command and hand back its "return" value
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, Error> {
+        let mut request = json!({ "execute": command });
+        if let Some(args) = arguments {
+            request["arguments"] = args;
+        }
+        let line = format!("{}\n", request);
+        trace!("QMP -> {}", line.trim());
+        self.writer.write_all(line.as_bytes())
+            .map_err(|e| qmp_error(format!("could not send {}: {}", command, e)))?;
+
+        loop {
+            let msg = self.read_message()?
+                .ok_or_else(|| qmp_error(format!("timed out waiting for {}", command)))?;
+            if msg.get("event").is_some() {
+                self.queue_event(msg);
+            } else if let Some(ret) = msg.get("return") {
+                return Ok(ret.clone());
+            } else if let Some(err) = msg.get("error") {
+                return Err(qmp_error(format!("{} failed: {} {}",
+                    command,
+                    err["class"].as_str().unwrap_or(""),
+                    err["desc"].as_str().unwrap_or(""))));
+            } else {
+                return Err(qmp_error(format!("unexpected reply {}", msg)));
+            }
+        }
+    }
+
+    fn execute_typed<T: DeserializeOwned>(&mut self, command: &str) -> Result<T, Error> {
+        let ret = self.execute(command, None)?;
+        serde_json::from_value(ret)
+            .map_err(|e| qmp_error(format!("bad reply to {}: {}", command, e)))
+    }
+
+    pub fn query_status(&mut self) -> Result<StatusInfo, Error> {
+        self.execute_typed("query-status")
+    }
+
+    pub fn query_block(&mut self) -> Result<Vec<BlockInfo>, Error> {
+        self.execute_typed("query-block")
+    }
+
+    pub fn query_chardev(&mut self) -> Result<Vec<ChardevInfo>, Error> {
+        self.execute_typed("query-chardev")
+    }
+
+    //ask the guest to shut down through acpi
+    pub fn system_powerdown(&mut self) -> Result<(), Error> {
+        self.execute("system_powerdown", None).map(|_| ())
+    }
+
+    pub fn quit(&mut self) -> Result<(), Error> {
+        self.execute("quit", None).map(|_| ())
+    }
+
+    //run a monitor (hmp) command, for things like savevm that have no plain qmp command
+    pub fn human_monitor_command(&mut self, command_line: &str) -> Result<String, Error> {
+        let ret = self.execute("human-monitor-command", Some(json!({ "command-line": command_line })))?;
+        Ok(ret.as_str().unwrap_or("").to_string())
+    }
+
+    //next async event, waiting up to timeout for one to arrive
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<QmpEvent>, Error> {
+        if let Some(ev) = self.events.pop_front() {
+            return Ok(Some(ev));
+        }
+        self.set_timeout(Some(timeout))?;
+        let msg = self.read_message();
+        self.set_timeout(Some(DEFAULT_TIMEOUT))?;
+        match msg? {
+            Some(m) if m.get("event").is_some() => {
+                self.queue_event(m);
+                Ok(self.events.pop_front())
+            },
+            Some(m) => {
+                warn!("QMP: ignoring unsolicited message {}", m);
+                Ok(None)
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+
+    //the qemu end of the socket, as a test script sees it
+    struct MockQemu {
+        reader: BufReader<UnixStream>,
+        writer: UnixStream,
+    }
+
+    impl MockQemu {
+        fn send(&mut self, msg: Value) {
+            self.writer.write_all(format!("{}\n", msg).as_bytes()).unwrap();
+        }
+
+        fn expect(&mut self, command: &str) {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let msg: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(msg["execute"], command);
+        }
+
+        //greeting and capabilities negotiation, as qemu does it
+        fn handshake(&mut self) {
+            self.send(json!({ "QMP": { "version": {}, "capabilities": [] } }));
+            self.expect("qmp_capabilities");
+            self.send(json!({ "return": {} }));
+        }
+    }
+
+    fn mock_socket(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("slquickemu-qmp-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn mock_qemu<F>(name: &str, script: F) -> (PathBuf, JoinHandle<()>)
+    where F: FnOnce(&mut MockQemu) + Send + 'static {
+        let path = mock_socket(name);
+        let listener = UnixListener::bind(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut qemu = MockQemu {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            };
+            script(&mut qemu);
+        });
+        (path, handle)
+    }
+
+    fn event(name: &str) -> Value {
+        json!({ "event": name, "data": {}, "timestamp": { "seconds": 1, "microseconds": 2 } })
+    }
+
+    #[test]
+    fn handshake_then_command() {
+        let (path, server) = mock_qemu("handshake", |qemu| {
+            qemu.handshake();
+            qemu.expect("query-status");
+            qemu.send(json!({ "return": { "status": "running", "running": true } }));
+        });
+        let mut client = QmpClient::connect(&path).unwrap();
+        let status = client.query_status().unwrap();
+        assert_eq!(status.status, "running");
+        assert!(status.running);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn bad_greeting() {
+        let (path, server) = mock_qemu("greeting", |qemu| {
+            qemu.send(json!({ "hello": "world" }));
+        });
+        let err = QmpClient::connect(&path).err().unwrap();
+        assert_eq!(err.kind(), error::ErrorKind::Qmp);
+        assert!(err.to_string().contains("unexpected greeting"));
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn event_between_command_and_reply() {
+        let (path, server) = mock_qemu("event", |qemu| {
+            qemu.handshake();
+            qemu.expect("query-status");
+            qemu.send(event("STOP"));
+            qemu.send(json!({ "return": { "status": "paused", "running": false } }));
+        });
+        let mut client = QmpClient::connect(&path).unwrap();
+        let status = client.query_status().unwrap();
+        assert_eq!(status.status, "paused");
+        //the event was queued, not lost or taken for the reply
+        let ev = client.next_event(Duration::from_millis(10)).unwrap().unwrap();
+        assert_eq!(ev.event, "STOP");
+        assert_eq!(ev.timestamp.microseconds, 2);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn error_reply() {
+        let (path, server) = mock_qemu("error", |qemu| {
+            qemu.handshake();
+            qemu.expect("human-monitor-command");
+            qemu.send(json!({ "error": { "class": "GenericError", "desc": "no such snapshot" } }));
+        });
+        let mut client = QmpClient::connect(&path).unwrap();
+        let err = client.human_monitor_command("loadvm nope").err().unwrap();
+        assert_eq!(err.kind(), error::ErrorKind::Qmp);
+        assert_eq!(err.to_string(), "QMP: human-monitor-command failed: GenericError no such snapshot");
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let (done, wait) = mpsc::channel::<()>();
+        let (path, server) = mock_qemu("timeout", move |qemu| {
+            qemu.handshake();
+            qemu.expect("query-status");
+            //never answer, but keep the socket open until the client gave up
+            let _ = wait.recv();
+        });
+        let mut client = QmpClient::connect(&path).unwrap();
+        client.set_timeout(Some(Duration::from_millis(100))).unwrap();
+        let err = client.query_status().err().unwrap();
+        assert!(err.to_string().contains("timed out waiting for query-status"));
+        assert!(client.next_event(Duration::from_millis(50)).unwrap().is_none());
+        done.send(()).unwrap();
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
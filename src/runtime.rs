@@ -0,0 +1,297 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * Every launched vm gets a directory under $XDG_RUNTIME_DIR/slquickemu/<vmname>/
+ * holding its pidfile, qmp socket, allocated ports and logs so later
+ * invocations can find and manage it.
+ */
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::qemuconfig;
+use crate::qemuargs::QemuArg;
+use crate::error::{Error, ErrorKind};
+
+const PID_FILE: &str = "pid";
+const QMP_SOCKET: &str = "qmp.sock";
+const PORTS_FILE: &str = "ports.toml";
+const SERIAL_FILE: &str = "serial.toml";
+const HELPERS_FILE: &str = "helpers.toml";
+const STDOUT_LOG: &str = "stdout.log";
+const STDERR_LOG: &str = "stderr.log";
+
+pub struct RuntimeDir {
+    pub path: PathBuf,
+}
+
+//a helper process started for the vm, e.g. swtpm
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HelperPid {
+    pub pid: u32,
+    pub binary: String,
+}
+
+pub fn get_runtime_base() -> Result<PathBuf, Error> {
+    let xdg = qemuconfig::get_xdg_runtime_dir()?;
+    Ok(Path::new(&xdg).join("slquickemu"))
+}
+
+//a zombie has exited, it just has not been reaped by its parent yet
+fn is_zombie(pid: i32) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat.rsplit(')').next()
+            .map(|rest| rest.trim_start().starts_with('Z'))
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+//true if a process with this pid exists
+pub fn pid_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    let r = unsafe { libc::kill(pid, 0) };
+    let exists = r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    exists && !is_zombie(pid)
+}
+
+/*
+ * A qemu that exits on its own leaves its pidfile behind, and the pid in
+ * it can be handed to an unrelated process later. Only a process running
+ * the recorded binary with this vm's -name counts as the vm.
+ */
+fn is_vm_process(pid: i32, vmname: &str, binary: Option<&str>) -> bool {
+    let cmdline = match fs::read(format!("/proc/{}/cmdline", pid)) {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+    let argv: Vec<String> = cmdline.split(|b| *b == 0)
+        .map(|a| String::from_utf8_lossy(a).to_string())
+        .collect();
+    if let Some(binary) = binary {
+        if !runs_binary(pid, &argv, binary) {
+            return false;
+        }
+    }
+    argv.windows(2).any(|w| w[0] == "-name" && QemuArg::parse("name", &w[1]).value.as_deref() == Some(vmname))
+}
+
+//a #! script runs as its interpreter, with the script among the arguments
+fn runs_binary(pid: i32, argv: &[String], binary: &str) -> bool {
+    let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok();
+    let same_exe = exe.is_some() && exe == fs::canonicalize(binary).ok();
+    same_exe || argv.iter().any(|a| a == binary)
+}
+
+//the same check for a helper, which has no -name to go by
+fn is_helper_process(pid: i32, binary: &str) -> bool {
+    match fs::read(format!("/proc/{}/cmdline", pid)) {
+        Ok(cmdline) => {
+            let argv: Vec<String> = cmdline.split(|b| *b == 0)
+                .map(|a| String::from_utf8_lossy(a).to_string())
+                .collect();
+            runs_binary(pid, &argv, binary)
+        },
+        Err(_) => false,
+    }
+}
+
+impl RuntimeDir {
+    //the directory for a vm, nothing is created
+    pub fn for_vm(vmname: &str) -> Result<RuntimeDir, Error> {
+        Ok(RuntimeDir {
+            path: get_runtime_base()?.join(vmname),
+        })
+    }
+
+    pub fn create(&self) -> Result<(), Error> {
+        match fs::create_dir_all(&self.path) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                Err(Error::new(ErrorKind::MissingXdgRuntime, format!("Could not create runtime dir {}", self.path.display())).with_source(e))
+            }
+        }
+    }
+
+    pub fn vmname(&self) -> String {
+        self.path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("")
+            .to_string()
+    }
+
+    pub fn pid_file(&self) -> PathBuf {
+        self.path.join(PID_FILE)
+    }
+
+    pub fn qmp_socket(&self) -> PathBuf {
+        self.path.join(QMP_SOCKET)
+    }
+
+    pub fn ports_file(&self) -> PathBuf {
+        self.path.join(PORTS_FILE)
+    }
+
+    pub fn serial_file(&self) -> PathBuf {
+        self.path.join(SERIAL_FILE)
+    }
+
+    pub fn helpers_file(&self) -> PathBuf {
+        self.path.join(HELPERS_FILE)
+    }
+
+    pub fn stdout_log(&self) -> PathBuf {
+        self.path.join(STDOUT_LOG)
+    }
+
+    pub fn stderr_log(&self) -> PathBuf {
+        self.path.join(STDERR_LOG)
+    }
+
+    //the pid and the binary it runs, one per line
+    pub fn write_pid(&self, pid: u32, binary: &str) -> Result<(), Error> {
+        write_file(&self.pid_file(), &format!("{}\n{}\n", pid, binary))
+    }
+
+    pub fn read_pid(&self) -> Option<i32> {
+        fs::read_to_string(self.pid_file()).ok()
+            .and_then(|s| s.lines().next().and_then(|l| l.trim().parse().ok()))
+    }
+
+    //older pidfiles only have the pid
+    fn read_binary(&self) -> Option<String> {
+        fs::read_to_string(self.pid_file()).ok()
+            .and_then(|s| s.lines().nth(1).map(|l| l.to_string()))
+            .filter(|b| !b.is_empty())
+    }
+
+    //pid of the vm if it is still running
+    pub fn running_pid(&self) -> Option<i32> {
+        match self.read_pid() {
+            Some(pid) if pid_alive(pid) => {
+                if is_vm_process(pid, &self.vmname(), self.read_binary().as_deref()) {
+                    Some(pid)
+                } else {
+                    debug!("pid {} of {} belongs to another process now", pid, self.vmname());
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    pub fn write_ports(&self, ports: &BTreeMap<String, u16>) -> Result<(), Error> {
+        let s = toml::to_string(ports).unwrap_or_default();
+        write_file(&self.ports_file(), &s)
+    }
+
+    pub fn read_ports(&self) -> BTreeMap<String, u16> {
+        fs::read_to_string(self.ports_file()).ok()
+            .and_then(|s| toml::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    //serialN -> where qemu put it, e.g. "pty:/dev/pts/3"
+    pub fn write_serial(&self, serial: &BTreeMap<String, String>) -> Result<(), Error> {
+        let s = toml::to_string(serial).unwrap_or_default();
+        write_file(&self.serial_file(), &s)
+    }
+
+    pub fn read_serial(&self) -> BTreeMap<String, String> {
+        fs::read_to_string(self.serial_file()).ok()
+            .and_then(|s| toml::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn write_helpers(&self, helpers: &BTreeMap<String, HelperPid>) -> Result<(), Error> {
+        let s = toml::to_string(helpers).unwrap_or_default();
+        write_file(&self.helpers_file(), &s)
+    }
+
+    pub fn read_helpers(&self) -> BTreeMap<String, HelperPid> {
+        fs::read_to_string(self.helpers_file()).ok()
+            .and_then(|s| toml::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    /*
+     * Helpers mostly exit once qemu disconnects from them, but not when
+     * qemu never got that far or they were started for a daemonized vm
+     * that was killed. Terminate whatever recorded helper is still there.
+     */
+    pub fn stop_helpers(&self) {
+        for (name, helper) in self.read_helpers().iter() {
+            let pid = helper.pid as i32;
+            if pid_alive(pid) && is_helper_process(pid, &helper.binary) {
+                debug!("Stopping {} helper {} (pid {})", self.vmname(), name, pid);
+                unsafe {
+                    libc::kill(pid, libc::SIGTERM);
+                }
+            }
+        }
+        if self.helpers_file().exists() {
+            let _ = fs::remove_file(self.helpers_file());
+        }
+    }
+
+    //drop everything that only makes sense while the vm runs, logs are kept
+    pub fn clear_state(&self) {
+        for f in [self.pid_file(), self.qmp_socket(), self.ports_file(), self.serial_file(), self.helpers_file()].iter() {
+            if f.exists() {
+                if let Err(e) = fs::remove_file(f) {
+                    warn!("Could not remove {}: {}", f.display(), e);
+                }
+            }
+        }
+    }
+}
+
+//every vm that has a runtime dir, running or not
+pub fn list_runtime_dirs() -> Result<Vec<RuntimeDir>, Error> {
+    let base = get_runtime_base()?;
+    let mut dirs = Vec::new();
+    if let Ok(entries) = fs::read_dir(&base) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            if entry.path().is_dir() {
+                dirs.push(RuntimeDir { path: entry.path() });
+            }
+        }
+    }
+    dirs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(dirs)
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), Error> {
+    match fs::write(path, contents) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            Err(Error::new(ErrorKind::Misc, format!("Could not write {}", path.display())).with_source(e))
+        }
+    }
+}
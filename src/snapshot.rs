@@ -0,0 +1,367 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * Named snapshots, the "Save State" TODO.
+ * A running vm is snapshotted through the monitor (savevm/loadvm/delvm),
+ * which includes the ram and device state. A stopped vm has its disks
+ * snapshotted with qemu-img, which only covers the disk contents.
+ */
+
+use std::path::Path;
+use crate::qemuconfig;
+use crate::qmp::{ImageInfo, QmpClient, SnapshotInfo};
+use crate::runtime::RuntimeDir;
+use crate::utils::run_qemu_img;
+use crate::error::{Error, ErrorKind};
+
+pub enum SnapshotAction {
+    Create,
+    List,
+    Apply,
+    Delete,
+}
+
+//the writable disk images of a vm that exist on disk
+pub fn disk_images(config: &qemuconfig::QuickEmuConfig) -> Vec<String> {
+    config.disks.iter()
+        .filter(|d| !d.readonly && Path::new(&d.path).exists())
+        .map(|d| d.path.clone())
+        .collect()
+}
+
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn format_date(secs: i64) -> String {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        libc::localtime_r(&t, &mut tm);
+        libc::strftime(buf.as_mut_ptr() as *mut libc::c_char, buf.len(),
+                       b"%Y-%m-%d %H:%M:%S\0".as_ptr() as *const libc::c_char, &tm)
+    };
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+fn format_vm_clock(secs: i64, nsecs: i64) -> String {
+    format!("{:02}:{:02}:{:02}.{:03}", secs / 3600, (secs / 60) % 60, secs % 60, nsecs / 1_000_000)
+}
+
+fn format_snapshots(snapshots: &[SnapshotInfo]) -> Vec<String> {
+    let mut lines = vec![format!("{:<4} {:<20} {:>10}  {:<19}  VM CLOCK", "ID", "NAME", "SIZE", "DATE")];
+    for s in snapshots.iter() {
+        lines.push(format!("{:<4} {:<20} {:>10}  {:<19}  {}", s.id, s.name, format_size(s.vm_state_size),
+                           format_date(s.date_sec), format_vm_clock(s.vm_clock_sec, s.vm_clock_nsec)));
+    }
+    lines
+}
+
+fn print_snapshots(snapshots: &[SnapshotInfo]) {
+    for line in format_snapshots(snapshots).iter() {
+        println!("{}", line);
+    }
+}
+
+//one entry per name, a snapshot usually exists on every disk
+fn merge_snapshots(all: Vec<SnapshotInfo>) -> Vec<SnapshotInfo> {
+    let mut merged: Vec<SnapshotInfo> = Vec::new();
+    for s in all.into_iter() {
+        match merged.iter_mut().find(|m| m.name == s.name) {
+            Some(m) => m.vm_state_size = m.vm_state_size.max(s.vm_state_size),
+            None => merged.push(s),
+        }
+    }
+    merged
+}
+
+fn online_snapshot(rt: &RuntimeDir, action: &SnapshotAction, name: &str) -> Result<(), Error> {
+    let mut qmp = QmpClient::connect(&rt.qmp_socket())?;
+    if let SnapshotAction::List = action {
+        let snapshots = qmp.query_block()?.into_iter()
+            .filter_map(|b| b.inserted)
+            .filter_map(|i| i.image)
+            .flat_map(|i| i.snapshots)
+            .collect();
+        print_snapshots(&merge_snapshots(snapshots));
+        return Ok(());
+    }
+    let hmp = match action {
+        SnapshotAction::Create => "savevm",
+        SnapshotAction::Apply => "loadvm",
+        _ => "delvm",
+    };
+    let out = qmp.human_monitor_command(&format!("{} {}", hmp, name))?;
+    //the monitor answers with an empty string on success
+    if out.contains("Error") {
+        return Err(Error::new(ErrorKind::Qmp, format!("{} {} failed: {}", hmp, name, out.trim())));
+    }
+    if !out.trim().is_empty() {
+        warn!("{}", out.trim());
+    }
+    Ok(())
+}
+
+fn read_image_info(config: &qemuconfig::QuickEmuConfig, disk: &str) -> Result<ImageInfo, Error> {
+    let out = run_qemu_img(&config.qemu_img_path, &["info", "--output=json", "-U", disk])?;
+    serde_json::from_str::<ImageInfo>(&out)
+        .map_err(|e| Error::new(ErrorKind::QemuImg, format!("Could not read snapshots of {}", disk)).with_source(e))
+}
+
+fn offline_snapshot(config: &qemuconfig::QuickEmuConfig, action: &SnapshotAction, name: &str) -> Result<(), Error> {
+    let disks = disk_images(config);
+    if disks.is_empty() {
+        return Err(Error::new(ErrorKind::NoSuchFile, format!("{} has no disk images to snapshot", config.vmname)));
+    }
+    if let SnapshotAction::List = action {
+        let mut snapshots = Vec::new();
+        for disk in disks.iter() {
+            match read_image_info(config, disk) {
+                Ok(info) => snapshots.extend(info.snapshots),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        print_snapshots(&merge_snapshots(snapshots));
+        return Ok(());
+    }
+    let mut images = Vec::new();
+    for disk in disks.iter() {
+        images.push((disk.clone(), read_image_info(config, disk)?));
+    }
+    check_offline_snapshot(&images, action, name)?;
+    let flag = match action {
+        SnapshotAction::Create => "-c",
+        SnapshotAction::Apply => "-a",
+        _ => "-d",
+    };
+    let mut done: Vec<&str> = Vec::new();
+    for disk in disks.iter() {
+        if let Err(e) = run_qemu_img(&config.qemu_img_path, &["snapshot", flag, name, disk]) {
+            return Err(partial_snapshot_error(config, action, name, &done, disk, e));
+        }
+        done.push(disk);
+    }
+    Ok(())
+}
+
+//every disk has to be able to take the change before any of them is changed
+fn check_offline_snapshot(images: &[(String, ImageInfo)], action: &SnapshotAction, name: &str) -> Result<(), Error> {
+    let disks_where = |f: &dyn Fn(&ImageInfo) -> bool| images.iter()
+        .filter(|(_, info)| f(info))
+        .map(|(disk, _)| disk.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ");
+    let has_name = |info: &ImageInfo| info.snapshots.iter().any(|s| s.name == name);
+    if let SnapshotAction::Create = action {
+        let not_qcow2 = disks_where(&|info| info.format.ne("qcow2"));
+        if !not_qcow2.is_empty() {
+            return Err(Error::new(ErrorKind::QemuImg, format!("Only qcow2 images can have snapshots, {} {} not, no disk was changed", not_qcow2, if not_qcow2.contains(", ") { "are" } else { "is" })));
+        }
+        let taken = disks_where(&has_name);
+        if !taken.is_empty() {
+            return Err(Error::new(ErrorKind::QemuImg, format!("Snapshot {} already exists on {}, no disk was changed", name, taken)));
+        }
+    } else {
+        let missing = disks_where(&|info| !has_name(info));
+        if !missing.is_empty() {
+            return Err(Error::new(ErrorKind::QemuImg, format!("Snapshot {} is missing on {}, no disk was changed", name, missing)));
+        }
+    }
+    Ok(())
+}
+
+/*
+ * A disk failed after the ones in done were changed. A new snapshot is
+ * taken off them again, an applied or deleted one can't be put back, so
+ * the error says which disks are no longer like the rest.
+ */
+fn partial_snapshot_error(config: &qemuconfig::QuickEmuConfig, action: &SnapshotAction, name: &str,
+                          done: &[&str], failed: &str, e: Error) -> Error {
+    let verb = match action {
+        SnapshotAction::Create => "Creating",
+        SnapshotAction::Apply => "Applying",
+        _ => "Deleting",
+    };
+    let mut message = format!("{} snapshot {} failed on {}", verb, name, failed);
+    if let SnapshotAction::Create = action {
+        let kept: Vec<&str> = done.iter()
+            .filter(|disk| run_qemu_img(&config.qemu_img_path, &["snapshot", "-d", name, disk]).is_err())
+            .copied()
+            .collect();
+        if !kept.is_empty() {
+            message += &format!(", it could not be removed again from {}", kept.join(", "));
+        }
+    } else if !done.is_empty() {
+        message += &format!(", {} {} changed already", done.join(", "), if done.len() == 1 { "was" } else { "were" });
+    }
+    Error::new(ErrorKind::QemuImg, message).with_source(e)
+}
+
+pub fn snapshot_vm(rt: &RuntimeDir, config: Option<&qemuconfig::QuickEmuConfig>,
+                   action: SnapshotAction, name: &str) -> Result<(), Error> {
+    if rt.running_pid().is_some() {
+        online_snapshot(rt, &action, name)?;
+    } else {
+        match config {
+            Some(c) => offline_snapshot(c, &action, name)?,
+            None => {
+                return Err(Error::new(ErrorKind::OpenConfigFile, format!("{} is not running, give its config file to snapshot the disks", rt.vmname())));
+            }
+        }
+    }
+    match action {
+        SnapshotAction::Create => println!("Created snapshot {} of {}", name, rt.vmname()),
+        SnapshotAction::Apply => println!("Applied snapshot {} to {}", name, rt.vmname()),
+        SnapshotAction::Delete => println!("Deleted snapshot {} of {}", name, rt.vmname()),
+        SnapshotAction::List => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn snapshot(name: &str, vm_state_size: u64) -> SnapshotInfo {
+        SnapshotInfo {
+            id: String::from("1"),
+            name: name.to_string(),
+            vm_state_size,
+            date_sec: 0,
+            vm_clock_sec: 3723,
+            vm_clock_nsec: 450_000_000,
+        }
+    }
+
+    fn image(format: &str, snapshots: &[&str]) -> ImageInfo {
+        let snapshots: Vec<String> = snapshots.iter()
+            .map(|s| format!("{{\"id\": \"1\", \"name\": \"{}\", \"vm-state-size\": 0, \"date-sec\": 0, \"vm-clock-sec\": 0, \"vm-clock-nsec\": 0}}", s))
+            .collect();
+        serde_json::from_str(&format!("{{\"format\": \"{}\", \"snapshots\": [{}]}}", format, snapshots.join(", "))).unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("slquickemu-snapshot-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn merge_keeps_one_snapshot_per_name() {
+        let merged = merge_snapshots(vec![snapshot("a", 10), snapshot("b", 0), snapshot("a", 30), snapshot("a", 20)]);
+        let names: Vec<&str> = merged.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(merged[0].vm_state_size, 30);
+        assert_eq!(merged[1].vm_state_size, 0);
+        assert!(merge_snapshots(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn sizes_and_clocks() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+        assert_eq!(format_size(u64::MAX), "16777216.0 TiB");
+        assert_eq!(format_vm_clock(3723, 450_000_000), "01:02:03.450");
+        assert_eq!(format_vm_clock(0, 999_999), "00:00:00.000");
+    }
+
+    #[test]
+    fn table_has_a_line_per_snapshot() {
+        let lines = format_snapshots(&[snapshot("clean-install", 1536)]);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("ID   NAME"));
+        assert!(lines[1].starts_with("1    clean-install"));
+        assert!(lines[1].contains("   1.5 KiB  "));
+        assert!(lines[1].ends_with("  01:02:03.450"));
+        assert_eq!(format_snapshots(&[]).len(), 1);
+    }
+
+    #[test]
+    fn create_needs_qcow2_disks_without_the_name() {
+        let images = vec![(String::from("a.qcow2"), image("qcow2", &[])), (String::from("b.qcow2"), image("qcow2", &["old"]))];
+        assert!(check_offline_snapshot(&images, &SnapshotAction::Create, "new").is_ok());
+        let e = check_offline_snapshot(&images, &SnapshotAction::Create, "old").unwrap_err();
+        assert!(e.to_string().contains("already exists on b.qcow2,"));
+        let raw = vec![(String::from("a.qcow2"), image("qcow2", &[])), (String::from("c.img"), image("raw", &[]))];
+        let e = check_offline_snapshot(&raw, &SnapshotAction::Create, "new").unwrap_err();
+        assert!(e.to_string().contains("c.img is not"));
+    }
+
+    #[test]
+    fn apply_and_delete_need_the_name_on_every_disk() {
+        let images = vec![(String::from("a.qcow2"), image("qcow2", &["old"])), (String::from("b.qcow2"), image("qcow2", &[]))];
+        for action in [SnapshotAction::Apply, SnapshotAction::Delete].iter() {
+            let e = check_offline_snapshot(&images, action, "old").unwrap_err();
+            assert!(e.to_string().contains("missing on b.qcow2,"));
+            assert!(check_offline_snapshot(&images[..1], action, "old").is_ok());
+        }
+    }
+
+    #[test]
+    fn failed_create_is_taken_off_the_earlier_disks() {
+        let log = temp_path("qemu-img.log");
+        let qemu_img = temp_path("qemu-img");
+        fs::write(&qemu_img, format!("#!/bin/sh\n\
+            echo \"$@\" >> {}\n\
+            case \"$1 $2 $4\" in\n\
+            info*) echo '{{\"format\": \"qcow2\"}}' ;;\n\
+            *-c*bad*) echo 'no space left' >&2; exit 1 ;;\n\
+            esac\n", log.display())).unwrap();
+        fs::set_permissions(&qemu_img, fs::Permissions::from_mode(0o755)).unwrap();
+        let good = temp_path("good.qcow2");
+        let bad = temp_path("bad.qcow2");
+        fs::write(&good, b"").unwrap();
+        fs::write(&bad, b"").unwrap();
+        let options = toml::from_str(&format!("qemu_img_path = \"{}\"\n[[disks]]\npath = \"{}\"\n[[disks]]\npath = \"{}\"\n",
+                                              qemu_img.display(), good.display(), bad.display())).unwrap();
+        let config = qemuconfig::from_options(options, "test.toml", Vec::new());
+
+        let e = offline_snapshot(&config, &SnapshotAction::Create, "snap").unwrap_err();
+        assert!(e.to_string().contains(&format!("failed on {}", bad.display())));
+        assert!(!e.to_string().contains("could not be removed"));
+        let calls = fs::read_to_string(&log).unwrap();
+        assert!(calls.ends_with(&format!("snapshot -d snap {}\n", good.display())));
+
+        for path in [log, qemu_img, good, bad].iter() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
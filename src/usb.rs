@@ -0,0 +1,225 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+/*
+ * USB host devices, as the kernel shows them under /sys/bus/usb/devices.
+ * The sysfs root can be moved with SLQUICKEMU_SYSFS_ROOT or --sysfs-root
+ * so a fake tree can stand in for the real one.
+ */
+
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use crate::error::{Error, ErrorKind};
+
+pub const SYSFS_ROOT_ENV: &str = "SLQUICKEMU_SYSFS_ROOT";
+
+#[derive(Debug, Clone)]
+pub struct UsbDevice {
+    pub busnum: u32,
+    pub devnum: u32,
+    pub vendor: String,
+    pub product: String,
+    pub manufacturer: String,
+    pub name: String,
+}
+
+impl UsbDevice {
+    //vendor:product the way lsusb and the usb_devices option write it
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.vendor, self.product)
+    }
+
+    pub fn dev_node(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", self.busnum, self.devnum))
+    }
+
+    //qemu opens the node read/write, so that is what the user needs
+    pub fn accessible(&self) -> bool {
+        let node = match CString::new(self.dev_node().as_os_str().as_bytes()) {
+            Ok(n) => n,
+            Err(_) => return false,
+        };
+        unsafe { libc::access(node.as_ptr(), libc::R_OK | libc::W_OK) == 0 }
+    }
+}
+
+pub fn sysfs_root() -> PathBuf {
+    match env::var(SYSFS_ROOT_ENV) {
+        Ok(root) if !root.is_empty() => PathBuf::from(root),
+        _ => PathBuf::from("/sys"),
+    }
+}
+
+fn read_attr(dir: &Path, attr: &str) -> String {
+    fs::read_to_string(dir.join(attr))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+//"046d:c52b" into its lower case vendor and product halves
+pub fn parse_usb_id(id: &str) -> Option<(String, String)> {
+    let mut parts = id.split(':');
+    let (vendor, product) = (parts.next()?, parts.next()?);
+    let is_id = |s: &str| s.len() == 4 && s.chars().all(|c| c.is_ascii_hexdigit());
+    if parts.next().is_some() || !is_id(vendor) || !is_id(product) {
+        return None;
+    }
+    Some((vendor.to_lowercase(), product.to_lowercase()))
+}
+
+fn read_devices(root: &Path) -> io::Result<Vec<UsbDevice>> {
+    let entries = fs::read_dir(root.join("bus/usb/devices"))?;
+    let mut devices = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        //interfaces look like 1-1:1.0, only whole devices have ids
+        if name.contains(':') {
+            continue;
+        }
+        let dir = entry.path();
+        let vendor = read_attr(&dir, "idVendor");
+        let product = read_attr(&dir, "idProduct");
+        if vendor.is_empty() || product.is_empty() {
+            continue;
+        }
+        devices.push(UsbDevice {
+            busnum: read_attr(&dir, "busnum").parse().unwrap_or(0),
+            devnum: read_attr(&dir, "devnum").parse().unwrap_or(0),
+            vendor: vendor.to_lowercase(),
+            product: product.to_lowercase(),
+            manufacturer: read_attr(&dir, "manufacturer"),
+            name: read_attr(&dir, "product"),
+        });
+    }
+    devices.sort_by_key(|d| (d.busnum, d.devnum));
+    Ok(devices)
+}
+
+pub fn list_devices(root: &Path) -> Result<Vec<UsbDevice>, Error> {
+    read_devices(root).map_err(|e| Error::new(ErrorKind::NoSuchFile, format!("Could not read {}", root.join("bus/usb/devices").display())).with_source(e))
+}
+
+//no sysfs means nothing is plugged in as far as we can tell
+pub fn find_devices(root: &Path, vendor: &str, product: &str) -> Vec<UsbDevice> {
+    match read_devices(root) {
+        Ok(devices) => devices.into_iter()
+            .filter(|d| d.vendor == vendor && d.product == product)
+            .collect(),
+        Err(e) => {
+            debug!("Could not read usb devices under {}: {}", root.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn print_devices(root: &Path) -> Result<(), Error> {
+    println!("{:<4} {:<4} {:<10} {:<6}  DEVICE", "BUS", "DEV", "ID", "ACCESS");
+    for d in list_devices(root)?.iter() {
+        let desc = format!("{} {}", d.manufacturer, d.name);
+        println!("{:03}  {:03}  {:<10} {:<6}  {}", d.busnum, d.devnum, d.id(),
+                 if d.accessible() { "yes" } else { "no" }, desc.trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a bus/usb/devices tree with the attributes read_devices looks at
+    fn fake_sysfs(name: &str, entries: &[(&str, &[(&str, &str)])]) -> PathBuf {
+        let root = env::temp_dir().join(format!("slquickemu-usb-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        for (entry, attrs) in entries.iter() {
+            let dir = root.join("bus/usb/devices").join(entry);
+            fs::create_dir_all(&dir).unwrap();
+            for (attr, value) in attrs.iter() {
+                fs::write(dir.join(attr), format!("{}\n", value)).unwrap();
+            }
+        }
+        root
+    }
+
+    fn sample_tree(name: &str) -> PathBuf {
+        fake_sysfs(name, &[
+            ("usb1", &[("idVendor", "1d6b"), ("idProduct", "0002"), ("busnum", "1"), ("devnum", "1")]),
+            ("1-1", &[("idVendor", "046D"), ("idProduct", "C52B"), ("busnum", "1"), ("devnum", "3"),
+                      ("manufacturer", "Logitech"), ("product", "USB Receiver")]),
+            //interfaces have no ids of their own, this one pretends to
+            ("1-1:1.0", &[("idVendor", "046d"), ("idProduct", "c52b"), ("busnum", "1"), ("devnum", "3")]),
+            ("2-1", &[("idVendor", "8087"), ("idProduct", "0024"), ("busnum", "2"), ("devnum", "2")]),
+            ("2-1.1", &[("busnum", "2"), ("devnum", "4")]),
+        ])
+    }
+
+    #[test]
+    fn read_whole_devices_only() {
+        let root = sample_tree("read");
+        let devices = read_devices(&root).unwrap();
+        let ids: Vec<String> = devices.iter().map(|d| d.id()).collect();
+        assert_eq!(ids, vec!["1d6b:0002", "046d:c52b", "8087:0024"]);
+        assert_eq!(devices[1].manufacturer, "Logitech");
+        assert_eq!(devices[1].name, "USB Receiver");
+        assert_eq!(devices[1].dev_node(), PathBuf::from("/dev/bus/usb/001/003"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn find_mixed_case_ids() {
+        let root = sample_tree("find");
+        let (vendor, product) = parse_usb_id("046D:c52B").unwrap();
+        let found = find_devices(&root, &vendor, &product);
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].busnum, found[0].devnum), (1, 3));
+        assert!(find_devices(&root, "dead", "beef").is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn no_sysfs() {
+        let root = env::temp_dir().join(format!("slquickemu-usb-{}-missing", std::process::id()));
+        assert!(find_devices(&root, "046d", "c52b").is_empty());
+        assert_eq!(list_devices(&root).unwrap_err().kind(), ErrorKind::NoSuchFile);
+    }
+
+    #[test]
+    fn usb_ids() {
+        assert_eq!(parse_usb_id("046d:c52b"), Some((String::from("046d"), String::from("c52b"))));
+        assert_eq!(parse_usb_id("046D:C52B"), Some((String::from("046d"), String::from("c52b"))));
+        assert_eq!(parse_usb_id("046d"), None);
+        assert_eq!(parse_usb_id("046d:"), None);
+        assert_eq!(parse_usb_id(":c52b"), None);
+        assert_eq!(parse_usb_id("46d:c52b"), None);
+        assert_eq!(parse_usb_id("046d:c52b0"), None);
+        assert_eq!(parse_usb_id("046d:c52b:1"), None);
+        assert_eq!(parse_usb_id("046g:c52b"), None);
+        assert_eq!(parse_usb_id("0x46:c52b"), None);
+        assert_eq!(parse_usb_id(""), None);
+    }
+}
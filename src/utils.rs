@@ -24,10 +24,14 @@
  */
 
 
-use std::fs::File;
-use std::io::{BufReader, BufRead};
-use std::net::TcpStream;
-use crate::qemuconfig;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufRead};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::Command;
+use crate::runtime;
+use crate::error::{Error, ErrorKind};
 
 pub fn get_system_memory() ->  u64 {
     let file = match File::open("/proc/meminfo") {
@@ -37,7 +41,7 @@ pub fn get_system_memory() ->  u64 {
 
     let reader = BufReader::new(file);
 
-    for line in reader.lines().filter_map(|result |result.ok()) {
+    for line in reader.lines().map_while(Result::ok) {
         debug!("{:?}",line);
         let mut it = line.split(':');
         let (key, value) = match (it.next(), it.next()) {
@@ -61,17 +65,37 @@ pub fn get_system_memory() ->  u64 {
     0
 }
 
+//look a helper binary up in PATH, daemons like smbd usually live in sbin
+pub fn find_binary(name: &str) -> Option<PathBuf> {
+    let path = env::var("PATH").unwrap_or_default();
+    let sbin = ["/usr/sbin", "/sbin", "/usr/local/sbin", "/usr/libexec", "/usr/lib/qemu"];
+    path.split(':')
+        .filter(|d| !d.is_empty())
+        .chain(sbin.iter().cloned())
+        .map(|d| PathBuf::from(d).join(name))
+        .find(|p| p.is_file())
+}
+
 fn socket_connect(port:u16) -> std::io::Result<()>
 {
     TcpStream::connect(format!("127.0.0.1:{}",port))?;
     Ok(())
 }
 
-pub fn find_open_socket(base_port: u16) -> Result<u16, qemuconfig::ERRORCODES>
+pub fn find_open_socket(base_port: u16) -> Result<u16, Error>
+{
+    find_open_socket_excluding(base_port, &[])
+}
+
+//as find_open_socket, skipping ports already handed out for this vm
+pub fn find_open_socket_excluding(base_port: u16, taken: &[u16]) -> Result<u16, Error>
 {
     for i in 1..=5
     {
         let port:u16 = i + base_port;
+        if taken.contains(&port) {
+            continue;
+        }
         debug!("Trying port {}",port);
         let r = socket_connect(port);
         match r {
@@ -79,6 +103,114 @@ pub fn find_open_socket(base_port: u16) -> Result<u16, qemuconfig::ERRORCODES>
             Err(_e) => return Ok(port),
         }
     }
-    error!("Exhausted open port search");
-    Err(qemuconfig::ERRORCODES::NoOpenPorts)
+    Err(Error::new(ErrorKind::NoOpenPorts, "Exhausted open port search"))
+}
+
+//something answers on the port or has it bound, any other bind failure is for the caller
+fn port_in_use(proto: &str, port: u16) -> io::Result<bool> {
+    if proto.ne("udp") && socket_connect(port).is_ok() {
+        return Ok(true);
+    }
+    let bound = match proto {
+        "udp" => UdpSocket::bind(("0.0.0.0", port)).map(|_| ()),
+        _ => TcpListener::bind(("0.0.0.0", port)).map(|_| ()),
+    };
+    match bound {
+        Ok(()) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+//socket inodes bound to a local port, from /proc/net/{tcp,udp}{,6}
+fn socket_inodes(proto: &str, port: u16) -> Vec<String> {
+    let mut inodes = Vec::new();
+    for table in [proto.to_string(), format!("{}6", proto)].iter() {
+        let contents = match fs::read_to_string(format!("/proc/net/{}", table)) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        for line in contents.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                continue;
+            }
+            let local_port = fields[1].rsplit(':').next()
+                .and_then(|p| u16::from_str_radix(p, 16).ok());
+            //tcp sockets have to be listening, 0A is TCP_LISTEN
+            if local_port == Some(port) && (proto != "tcp" || fields[3] == "0A") {
+                inodes.push(fields[9].to_string());
+            }
+        }
+    }
+    inodes
+}
+
+//"pid 1234 (sshd)" for whoever holds the port, only works for our own processes
+fn find_port_process(proto: &str, port: u16) -> Option<String> {
+    let inodes = socket_inodes(proto, port);
+    if inodes.is_empty() {
+        return None;
+    }
+    let targets: Vec<String> = inodes.iter().map(|i| format!("socket:[{}]", i)).collect();
+    for proc_entry in fs::read_dir("/proc").ok()?.filter_map(|e| e.ok()) {
+        let pid = proc_entry.file_name().to_string_lossy().to_string();
+        if !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let fds = match fs::read_dir(proc_entry.path().join("fd")) {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+        for fd in fds.filter_map(|e| e.ok()) {
+            let link = fs::read_link(fd.path()).map(|l| l.to_string_lossy().to_string());
+            if let Ok(link) = link {
+                if targets.contains(&link) {
+                    let comm = fs::read_to_string(proc_entry.path().join("comm")).unwrap_or_default();
+                    return Some(format!("pid {} ({})", pid, comm.trim()));
+                }
+            }
+        }
+    }
+    None
+}
+
+/*
+ * Who is sitting on a host port, None if it is free.
+ * Ports recorded by our own running vms count even if qemu
+ * hasn't bound them yet. A port we may not bind at all, one
+ * below ip_unprivileged_port_start without root, is an error
+ * rather than taken.
+ */
+pub fn port_owner(proto: &str, port: u16) -> Result<Option<String>, Error> {
+    if let Ok(dirs) = runtime::list_runtime_dirs() {
+        for rt in dirs.iter().filter(|rt| rt.running_pid().is_some()) {
+            if rt.read_ports().values().any(|p| *p == port) {
+                return Ok(Some(format!("vm {}", rt.vmname())));
+            }
+        }
+    }
+    match port_in_use(proto, port) {
+        Ok(true) => Ok(Some(find_port_process(proto, port).unwrap_or_else(|| String::from("an unknown process")))),
+        Ok(false) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            Err(Error::new(ErrorKind::PrivilegedPort, format!("{} host port {} is privileged, only root can forward it, use a port above 1023", proto, port)))
+        },
+        Err(e) => Err(Error::new(ErrorKind::PortInUse, format!("Can't bind {} host port {}", proto, port)).with_source(e)),
+    }
+}
+
+//qemu-img with its output, a failure carries what it printed on stderr
+pub fn run_qemu_img(qemu_img_path: &str, args: &[&str]) -> Result<String, Error> {
+    debug!("Running {} {:?}", qemu_img_path, args);
+    let r = match Command::new(qemu_img_path).args(args).output() {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(Error::new(ErrorKind::QemuImg, format!("Failed to run {}", qemu_img_path)).with_source(e));
+        }
+    };
+    if !r.status.success() {
+        return Err(Error::new(ErrorKind::QemuImg, format!("{} {} failed: {}", qemu_img_path, args.join(" "), String::from_utf8_lossy(&r.stderr).trim())));
+    }
+    Ok(String::from_utf8_lossy(&r.stdout).to_string())
 }
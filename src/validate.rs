@@ -0,0 +1,564 @@
+/*
+ *
+        Copyright (C) 2020 Emmybear Arcades
+
+        This program is free software; you can redistribute it and/or
+        modify it under the terms of the GNU General Public License
+        as published by the Free Software Foundation; version 2
+        of the License.
+
+        This program is distributed in the hope that it will be useful,
+        but WITHOUT ANY WARRANTY; without even the implied warranty of
+        MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+
+        See the GNU General Public License for more details.
+
+        You should have received a copy of the GNU General Public License
+        along with this program; if not, write to:
+
+                Free Software Foundation, Inc.
+                59 Temple Place - Suite 330
+                Boston, MA  02111-1307, USA
+
+ *
+ */
+
+
+/*
+ * Config validation. Each rule looks at the merged config and adds what it
+ * finds to a report, so every problem shows up at once instead of the
+ * first one the qemu command builder trips over. Errors are things qemu
+ * would refuse or that would fail at start, warnings are things that would
+ * start but probably not the way the user meant.
+ */
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use crate::configenum::DiskInterface;
+use crate::qemuconfig::{self, QuickEmuConfig};
+use crate::usb;
+use crate::utils::{find_binary, get_system_memory};
+use crate::error::{Error, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub severity: Severity,
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}[{}]: {}", severity, self.rule, self.message)
+    }
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    rule: &'static str,
+}
+
+impl Report {
+    fn error(&mut self, message: String) {
+        self.problems.push(Problem { severity: Severity::Error, rule: self.rule, message });
+    }
+
+    fn warn(&mut self, message: String) {
+        self.problems.push(Problem { severity: Severity::Warning, rule: self.rule, message });
+    }
+
+    pub fn errors(&self) -> usize {
+        self.problems.iter().filter(|p| p.severity == Severity::Error).count()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.problems.iter().filter(|p| p.severity == Severity::Warning).count()
+    }
+
+    //--strict, unknown keys count as errors
+    fn strict(&mut self) {
+        for problem in self.problems.iter_mut().filter(|p| p.rule == "keys") {
+            problem.severity = Severity::Error;
+        }
+    }
+}
+
+const KVM_NODE: &str = "/dev/kvm";
+
+type Rule = fn(&QuickEmuConfig, &mut Report);
+
+//rule name, as it shows up in the report, and the check
+const RULES: [(&str, Rule); 14] = [
+    ("keys", check_keys),
+    ("binaries", check_binaries),
+    ("kvm", check_kvm),
+    ("memory", check_memory),
+    ("cpu", check_cpu),
+    ("machine", check_machine),
+    ("boot", check_boot),
+    ("files", check_files),
+    ("disks", check_disks),
+    ("drive-slots", check_drive_slots),
+    ("network", check_network),
+    ("usb", check_usb),
+    ("serial", check_serial),
+    ("shares", check_shares),
+];
+
+pub fn validate(config: &QuickEmuConfig) -> Report {
+    let mut report = Report::default();
+    for (name, rule) in RULES.iter() {
+        report.rule = name;
+        rule(config, &mut report);
+    }
+    report
+}
+
+fn check_keys(config: &QuickEmuConfig, report: &mut Report) {
+    for key in config.unknown_keys.iter() {
+        report.warn(key.to_string());
+    }
+}
+
+//a bare name is looked up like a shell would, a path has to exist
+fn binary_exists(binary: &str) -> bool {
+    if binary.contains('/') {
+        Path::new(binary).is_file()
+    } else {
+        find_binary(binary).is_some()
+    }
+}
+
+fn check_binaries(config: &QuickEmuConfig, report: &mut Report) {
+    if !binary_exists(&config.qemu_path) {
+        report.error(format!("qemu_path {} does not exist", config.qemu_path));
+    }
+    //qemu-img is only needed for images that don't exist yet
+    if !binary_exists(&config.qemu_img_path) {
+        if config.disks.iter().any(|d| !Path::new(&d.path).exists()) {
+            report.error(format!("qemu_img_path {} does not exist, it is needed to create the missing disks", config.qemu_img_path));
+        } else {
+            report.warn(format!("qemu_img_path {} does not exist, snapshots and disk commands won't work", config.qemu_img_path));
+        }
+    }
+}
+
+fn check_kvm(config: &QuickEmuConfig, report: &mut Report) {
+    check_kvm_node(config, report, Path::new(KVM_NODE));
+}
+
+fn check_kvm_node(config: &QuickEmuConfig, report: &mut Report, kvm: &Path) {
+    if !config.kvm {
+        return;
+    }
+    if !kvm.exists() {
+        report.error(format!("kvm is on but {} does not exist, load the kvm module or set kvm = false", kvm.display()));
+        return;
+    }
+    let node = CString::new(kvm.as_os_str().as_bytes()).unwrap();
+    if unsafe { libc::access(node.as_ptr(), libc::R_OK | libc::W_OK) } != 0 {
+        report.error(format!("kvm is on but {} can't be opened, add yourself to the kvm group", kvm.display()));
+    }
+}
+
+fn check_memory(config: &QuickEmuConfig, report: &mut Report) {
+    if config.ram.eq("auto") {
+        return;
+    }
+    let ram = match qemuconfig::get_ram_size(&config.ram) {
+        Ok(r) => r,
+        Err(e) => {
+            report.error(e.to_string());
+            return;
+        }
+    };
+    let host = get_system_memory() * 1024;
+    if host == 0 {
+        return;
+    }
+    if ram > host {
+        report.error(format!("RAM {} is more than the host's {}M", config.ram, host >> 20));
+    } else if ram > host / 4 * 3 {
+        report.warn(format!("RAM {} leaves less than a quarter of the host's {}M for the host", config.ram, host >> 20));
+    }
+}
+
+fn check_cpu(config: &QuickEmuConfig, report: &mut Report) {
+    let host = num_cpus::get();
+    if config.cpu_cores as usize > host {
+        report.warn(format!("cpu_cores {} is more than the {} cpus the host has", config.cpu_cores, host));
+    }
+}
+
+//the devices that can't go on isapc are checked with the devices
+fn check_machine(config: &QuickEmuConfig, report: &mut Report) {
+    if let Err(e) = qemuconfig::check_machine(config) {
+        report.error(e.to_string());
+    }
+}
+
+fn check_boot(config: &QuickEmuConfig, report: &mut Report) {
+    let checks = [
+        qemuconfig::check_ovmf(config),
+        qemuconfig::check_tpm(config),
+        qemuconfig::check_swtpm(config),
+    ];
+    for e in checks.iter().filter_map(|c| c.as_ref().err()) {
+        report.error(e.to_string());
+    }
+}
+
+fn check_files(config: &QuickEmuConfig, report: &mut Report) {
+    for (key, path) in [("iso", &config.iso), ("driver_iso", &config.driver_iso), ("floppy", &config.floppy)].iter() {
+        if !path.is_empty() && !Path::new(path).exists() {
+            report.error(format!("{} {} does not exist", key, path));
+        }
+    }
+}
+
+fn check_disks(config: &QuickEmuConfig, report: &mut Report) {
+    let mut paths = HashSet::new();
+    for (n, disk) in config.disks.iter().enumerate() {
+        if !paths.insert(&disk.path) {
+            report.error(format!("{} is disk {} and an earlier disk too", disk.path, n));
+        }
+        let checks = [
+            qemuconfig::check_disk_interface(config, disk),
+            qemuconfig::get_disk_serial(disk, n, false).map(|_| ()),
+            qemuconfig::check_cache_aio(disk),
+            qemuconfig::check_drive_tuning(disk),
+        ];
+        for e in checks.iter().filter_map(|c| c.as_ref().err()) {
+            report.error(e.to_string());
+        }
+        let format = if Path::new(&disk.path).exists() {
+            qemuconfig::get_existing_disk_format(disk)
+        } else {
+            qemuconfig::get_new_disk_format(disk)
+        };
+        if let Err(e) = format {
+            report.error(e.to_string());
+        }
+        for warning in qemuconfig::get_disk_warnings(config, disk) {
+            report.warn(warning);
+        }
+    }
+}
+
+//ide disks and the cdroms share the ide slots, scsi and ahci have their own limits
+fn check_drive_slots(config: &QuickEmuConfig, report: &mut Report) {
+    let count = |f: &dyn Fn(DiskInterface) -> bool| config.disks.iter()
+        .filter(|d| f(qemuconfig::get_disk_interface(config, d)))
+        .count();
+    let ide = count(&|i| i.is_ide());
+    let cdroms = [&config.iso, &config.driver_iso].iter().filter(|c| !c.is_empty()).count();
+    let slots = qemuconfig::get_ide_slots(config) as usize;
+    if ide + cdroms > slots {
+        report.error(format!("{} IDE disks and {} cdroms don't fit the {} IDE slots", ide, cdroms, slots));
+    }
+    let ahci = count(&|i| i == DiskInterface::Ahci);
+    if ahci > qemuconfig::AHCI_PORTS as usize {
+        report.error(format!("{} AHCI disks don't fit the {} AHCI ports", ahci, qemuconfig::AHCI_PORTS));
+    }
+    let scsi = count(&|i| i == DiskInterface::Scsi);
+    if let Ok((controller, _, limit)) = qemuconfig::get_scsi_controller(config) {
+        if scsi > limit as usize {
+            report.error(format!("{} SCSI disks don't fit the {} ids of {}", scsi, limit, controller.value.unwrap_or_default()));
+        }
+    }
+}
+
+fn check_network(config: &QuickEmuConfig, report: &mut Report) {
+    let mut checks = vec![
+        qemuconfig::check_network_backend(config),
+        qemuconfig::check_user_network(config),
+    ];
+    if config.network.ne("none") {
+        checks.push(qemuconfig::get_nic_device(config).map(|_| ()));
+    }
+    checks.push(qemuconfig::check_smb_share(config));
+    let mut seen = Vec::new();
+    for fwd in config.port_forwards.iter() {
+        match qemuconfig::check_port_forward(fwd, &mut seen) {
+            Ok(()) => checks.push(qemuconfig::check_host_port(config, fwd)),
+            Err(e) => checks.push(Err(e)),
+        }
+    }
+    for e in checks.iter().filter_map(|c| c.as_ref().err()) {
+        report.error(e.to_string());
+    }
+}
+
+fn check_usb(config: &QuickEmuConfig, report: &mut Report) {
+    if let Err(e) = qemuconfig::get_usb_controller_device(config) {
+        report.error(e.to_string());
+    }
+    let root = usb::sysfs_root();
+    for id in config.usb_devices.iter() {
+        match qemuconfig::check_usb_device(&root, id) {
+            Ok(true) => (),
+            Ok(false) => report.warn(qemuconfig::usb_unplugged_warning(id)),
+            Err(e) => report.error(e.to_string()),
+        }
+    }
+}
+
+fn check_serial(config: &QuickEmuConfig, report: &mut Report) {
+    for (i, serial) in config.serial.iter().enumerate() {
+        if let Err(e) = qemuconfig::check_serial_port(i, serial) {
+            report.error(e.to_string());
+        }
+    }
+}
+
+fn check_shares(config: &QuickEmuConfig, report: &mut Report) {
+    for share in config.shares.iter() {
+        if let Err(e) = qemuconfig::check_share(share).and_then(|_| qemuconfig::check_share_host(share)) {
+            report.error(e.to_string());
+        }
+    }
+}
+
+//shown whatever the log level, a misspelt key does nothing and is easy to miss otherwise
+pub fn report_unknown_keys(config: &QuickEmuConfig, strict: bool) -> Result<(), Error> {
+    let mut report = Report { problems: Vec::new(), rule: "keys" };
+    check_keys(config, &mut report);
+    if strict {
+        report.strict();
+    }
+    for problem in report.problems.iter() {
+        eprintln!("{}", problem);
+    }
+    if report.errors() > 0 {
+        return Err(Error::new(ErrorKind::InvalidConfig, format!("{} has {} unknown key(s)", config.vmname, report.errors())));
+    }
+    Ok(())
+}
+
+/*
+ * Run before starting a vm. A config with errors gets all of its problems
+ * logged at once, a good one stays quiet as the command builder warns
+ * about the same things on its own.
+ */
+pub fn validate_for_start(config: &QuickEmuConfig) -> Result<(), Error> {
+    let mut report = validate(config);
+    //report_unknown_keys has shown these already
+    report.problems.retain(|p| p.rule != "keys");
+    for problem in report.problems.iter() {
+        match problem.severity {
+            Severity::Warning => warn!("{}", problem),
+            Severity::Error => error!("{}", problem),
+        }
+    }
+    if report.errors() == 0 {
+        return Ok(());
+    }
+    Err(Error::new(ErrorKind::InvalidConfig, format!("{} has {} error(s)", config.vmname, report.errors())))
+}
+
+pub fn check_config(path: &str, strict: bool) -> Result<(), Error> {
+    let config = qemuconfig::setup_options(path)?;
+    let mut report = validate(&config);
+    if strict {
+        report.strict();
+    }
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    println!("{}: {} error(s), {} warning(s)", path, report.errors(), report.warnings());
+    if report.errors() > 0 {
+        return Err(Error::new(ErrorKind::InvalidConfig, format!("{} has {} error(s)", path, report.errors())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::configkeys::UnknownKey;
+
+    fn config(text: &str) -> QuickEmuConfig {
+        let options = toml::from_str(text).unwrap();
+        qemuconfig::from_options(options, "test.toml", Vec::new())
+    }
+
+    //a path of our own in the temp dir, nothing is created
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("slquickemu-validate-{}-{}", std::process::id(), name))
+    }
+
+    //what one rule finds, the errors then the warnings
+    fn run(rule: &str, config: &QuickEmuConfig) -> (Vec<String>, Vec<String>) {
+        let (name, check) = RULES.iter().find(|(n, _)| *n == rule).unwrap();
+        let mut report = Report { problems: Vec::new(), rule: name };
+        check(config, &mut report);
+        let messages = |severity| report.problems.iter()
+            .filter(|p| p.severity == severity)
+            .map(|p| p.message.clone())
+            .collect::<Vec<String>>();
+        (messages(Severity::Error), messages(Severity::Warning))
+    }
+
+    fn errors(rule: &str, text: &str) -> Vec<String> {
+        run(rule, &config(text)).0
+    }
+
+    fn warnings(rule: &str, text: &str) -> Vec<String> {
+        run(rule, &config(text)).1
+    }
+
+    #[test]
+    fn every_rule_passes_a_plain_config() {
+        let plain = config("kvm = false\nram = \"64M\"\ncpu_cores = 1\n");
+        for (name, _) in RULES.iter().filter(|(n, _)| *n != "binaries") {
+            assert_eq!(run(name, &plain), (Vec::new(), Vec::new()), "rule {}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_warnings_until_strict() {
+        let mut c = config("");
+        c.unknown_keys.push(UnknownKey {
+            file: String::from("vm.toml"),
+            section: String::new(),
+            key: String::from("cpu_core"),
+            suggestion: Some(String::from("cpu_cores")),
+        });
+        let mut report = Report { problems: Vec::new(), rule: "keys" };
+        check_keys(&c, &mut report);
+        assert_eq!((report.errors(), report.warnings()), (0, 1));
+        report.strict();
+        assert_eq!((report.errors(), report.warnings()), (1, 0));
+    }
+
+    #[test]
+    fn missing_binaries() {
+        let e = errors("binaries", "qemu_path = \"/nonexistent/qemu\"\nqemu_img_path = \"/nonexistent/qemu-img\"\n[[disks]]\npath = \"/nonexistent/vm.qcow2\"\n");
+        assert_eq!(e.len(), 2);
+        assert!(e[1].contains("needed to create the missing disks"));
+        //with nothing to create a missing qemu-img only costs the disk commands
+        let image = temp_path("binaries.qcow2");
+        fs::write(&image, "").unwrap();
+        let w = warnings("binaries", &format!("qemu_path = \"/bin/sh\"\nqemu_img_path = \"/nonexistent/qemu-img\"\n[[disks]]\npath = \"{}\"\n", image.display()));
+        fs::remove_file(&image).unwrap();
+        assert_eq!(w.len(), 1);
+    }
+
+    #[test]
+    fn kvm_without_dev_kvm() {
+        let c = config("kvm = true");
+        let mut report = Report::default();
+        check_kvm_node(&c, &mut report, &temp_path("kvm"));
+        assert_eq!(report.errors(), 1);
+        assert!(report.problems[0].message.contains("does not exist"));
+        let mut report = Report::default();
+        check_kvm_node(&config("kvm = false"), &mut report, &temp_path("kvm"));
+        assert_eq!(report.errors(), 0);
+    }
+
+    #[test]
+    fn ram_above_host_memory() {
+        assert_eq!(errors("memory", "ram = \"1024T\"").len(), 1);
+        assert_eq!(errors("memory", "ram = \"lots\"").len(), 1);
+        assert!(errors("memory", "ram = \"auto\"").is_empty());
+    }
+
+    #[test]
+    fn more_cores_than_the_host() {
+        if num_cpus::get() < 255 {
+            assert_eq!(warnings("cpu", "cpu_cores = 255").len(), 1);
+        }
+    }
+
+    #[test]
+    fn isa_on_q35() {
+        let e = errors("machine", "machine = \"q35\"\ndisplay_device = \"isa-vga\"");
+        assert_eq!(e.len(), 1);
+        assert!(e[0].contains("NEED THE isapc MACHINE"));
+        //left out, the machine follows the isa devices
+        let c = config("display_device = \"isa-vga\"");
+        assert_eq!(c.machine, "isapc");
+        assert_eq!(run("machine", &c), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn boot_needs_the_right_machine() {
+        let e = errors("boot", "machine = \"pc\"\nsecure_boot = true");
+        assert!(e.iter().any(|m| m.contains("SECURE BOOT NEEDS THE q35 MACHINE")));
+        let e = errors("boot", "machine = \"isapc\"\ntpm = true");
+        assert!(e.iter().any(|m| m.contains("CAN'T HAVE A TPM")));
+    }
+
+    #[test]
+    fn missing_iso() {
+        let e = errors("files", "iso = \"/nonexistent/install.iso\"");
+        assert_eq!(e, vec![String::from("iso /nonexistent/install.iso does not exist")]);
+    }
+
+    #[test]
+    fn scsi_without_a_controller() {
+        let e = errors("disks", "scsi_controller = \"\"\n[[disks]]\npath = \"/nonexistent/a.qcow2\"\ninterface = \"scsi\"\n");
+        assert_eq!(e.len(), 1);
+        assert!(e[0].contains("SCSI CONTROLLER"));
+    }
+
+    #[test]
+    fn bad_disks() {
+        let e = errors("disks", "[[disks]]\npath = \"/nonexistent/a.qcow2\"\ninterface = \"ide\"\nreadonly = true\n[[disks]]\npath = \"/nonexistent/a.qcow2\"\n");
+        assert!(e.iter().any(|m| m.contains("CAN'T BE READ ONLY")));
+        assert!(e.iter().any(|m| m.contains("and an earlier disk too")));
+    }
+
+    #[test]
+    fn too_many_ide_drives() {
+        let disks: String = (0..4).map(|i| format!("[[disks]]\npath = \"/nonexistent/{}.qcow2\"\ninterface = \"ide\"\n", i)).collect();
+        let e = errors("drive-slots", &format!("machine = \"pc\"\niso = \"/nonexistent/install.iso\"\n{}", disks));
+        assert_eq!(e, vec![String::from("4 IDE disks and 1 cdroms don't fit the 4 IDE slots")]);
+    }
+
+    #[test]
+    fn bad_port_forwards() {
+        let e = errors("network", "port_forwards = [ { proto = \"sctp\", host = 40022, guest = 22 } ]");
+        assert!(e[0].contains("PORT FORWARD PROTOCOL sctp IS UNKNOWN"));
+        let e = errors("network", "network = \"tap\"\nport_forwards = [ { host = 40022, guest = 22 } ]");
+        assert!(e.iter().any(|m| m.contains("ONLY WORK WITH USER NETWORKING")));
+    }
+
+    #[test]
+    fn bad_usb_id() {
+        let e = errors("usb", "usb_devices = [\"logitech\"]");
+        assert_eq!(e.len(), 1);
+        assert!(e[0].contains("IS NOT A vendor:product ID"));
+    }
+
+    #[test]
+    fn bad_serial_ports() {
+        let e = errors("serial", "[[serial]]\nbackend = \"modem\"\n[[serial]]\nbackend = \"file\"\n");
+        assert_eq!(e.len(), 2);
+        assert!(e[1].contains("NEEDS A path"));
+    }
+
+    #[test]
+    fn bad_shares() {
+        let e = errors("shares", "[[shares]]\npath = \"/nonexistent/share\"\ndriver = \"nfs\"\n");
+        assert!(e[0].contains("SHARE DRIVER nfs IS UNKNOWN"));
+        let e = errors("shares", "[[shares]]\npath = \"/nonexistent/share\"\ndriver = \"9p\"\n");
+        assert!(e[0].contains("is not a directory"));
+    }
+}